@@ -0,0 +1,213 @@
+use ratatui::style::{Color,Modifier,Style};
+use ratatui::text::{Line,Span};
+
+pub const ESCAPE:char = '\u{001B}';
+
+/// Whether `sequence`, starting with ESC, has been received in full. Control sequences
+/// (`ESC [`) end in a character from `@` to `~`; any other escape is two characters long.
+pub fn sequence_complete(sequence:&str) -> bool {
+    let mut chars = sequence.chars().skip(1);
+    match chars.next() {
+        None => false,
+        Some('[') => chars.any(|ch| ('@'..='~').contains(&ch)),
+        Some(_) => true,
+    }
+}
+
+/// Length in bytes of the escape sequence at the start of `text`, or of all of it if the
+/// sequence isn't finished.
+fn sequence_len(text:&str) -> usize {
+    let mut chars = text.char_indices().skip(1);
+    match chars.next() {
+        Some((_,'[')) => chars.find(|(_,ch)| ('@'..='~').contains(ch))
+            .map_or(text.len(), |(index,ch)| index + ch.len_utf8()),
+        Some((index,ch)) => index + ch.len_utf8(),
+        None => text.len(),
+    }
+}
+
+/// The byte position of the `column`th character of `line` that is shown, skipping over
+/// escape sequences, and that character. None if the line is shorter than that.
+pub fn visible_position(line:&str, column:usize) -> Option<(usize,char)> {
+    let mut index = 0;
+    let mut seen = 0;
+    while let Some(ch) = line[index..].chars().next() {
+        if ch == ESCAPE {
+            index += sequence_len(&line[index..]);
+        } else if seen == column {
+            return Some((index,ch));
+        } else {
+            seen += 1;
+            index += ch.len_utf8();
+        }
+    }
+    None
+}
+
+/// The standard eight colors, in the order their SGR codes go.
+const COLORS:[Color;8] = [Color::Black,Color::Red,Color::Green,Color::Yellow,Color::Blue,Color::Magenta,Color::Cyan,Color::Gray];
+const BRIGHT_COLORS:[Color;8] = [Color::DarkGray,Color::LightRed,Color::LightGreen,Color::LightYellow,Color::LightBlue,Color::LightMagenta,Color::LightCyan,Color::White];
+
+/// Apply the parameters of a Select Graphic Rendition sequence (`ESC [ ... m`) to `style`.
+/// Only the basic attributes and the 16 standard colors are understood; anything else is
+/// ignored.
+fn apply_sgr(style:Style, parameters:&str) -> Style {
+    parameters.split(';').fold(style, |style,parameter| {
+        match parameter.parse::<usize>().unwrap_or(0) {
+            0 => Style::default(),
+            1 => style.add_modifier(Modifier::BOLD),
+            2 => style.add_modifier(Modifier::DIM),
+            3 => style.add_modifier(Modifier::ITALIC),
+            4 => style.add_modifier(Modifier::UNDERLINED),
+            7 => style.add_modifier(Modifier::REVERSED),
+            22 => style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+            23 => style.remove_modifier(Modifier::ITALIC),
+            24 => style.remove_modifier(Modifier::UNDERLINED),
+            27 => style.remove_modifier(Modifier::REVERSED),
+            code @ 30..=37 => style.fg(COLORS[code - 30]),
+            39 => Style { fg: None, ..style },
+            code @ 40..=47 => style.bg(COLORS[code - 40]),
+            49 => Style { bg: None, ..style },
+            code @ 90..=97 => style.fg(BRIGHT_COLORS[code - 90]),
+            code @ 100..=107 => style.bg(BRIGHT_COLORS[code - 100]),
+            _ => style,
+        }
+    })
+}
+
+/// Turn one line of output with escape sequences in it into a styled line, leaving the
+/// sequences themselves out. `style` is the style in effect at the start of the line; the
+/// one in effect at its end is returned along with it.
+fn style_line(line:&str, mut style:Style) -> (Line<'static>,Style) {
+    let mut spans = Vec::new();
+    let mut rest = line;
+    while !rest.is_empty() {
+        match rest.find(ESCAPE) {
+            Some(0) => {
+                let length = sequence_len(rest);
+                let sequence = &rest[..length];
+                if let Some(parameters) = sequence.strip_prefix("\u{001B}[").and_then(|sequence| sequence.strip_suffix('m')) {
+                    style = apply_sgr(style, parameters);
+                }
+                rest = &rest[length..];
+            },
+            found => {
+                let end = found.unwrap_or(rest.len());
+                spans.push(Span::styled(String::from(&rest[..end]), style));
+                rest = &rest[end..];
+            },
+        }
+    }
+    (Line::from(spans), style)
+}
+
+/// Turn lines of output with escape sequences in them into styled lines, leaving the
+/// sequences themselves out. A style carries on into the lines after it, so every line is
+/// looked at, but only the last `count` are returned.
+pub fn styled_lines(lines:&[String], count:usize) -> Vec<Line<'static>> {
+    let mut style = Style::default();
+    let mut styled = Vec::with_capacity(lines.len());
+    for line in lines {
+        let (line,end_style) = style_line(line, style);
+        styled.push(line);
+        style = end_style;
+    }
+    styled.split_off(styled.len().saturating_sub(count))
+}
+
+/// Styled lines of the terminal, kept from one frame to the next. Output only ever changes
+/// the last line or adds new ones, so every line before the last is styled just once.
+#[derive(Debug,Default)]
+pub struct StyledTerminal {
+    /// Every line but the last one, styled, with the style in effect at its end.
+    finished:Vec<(Line<'static>,Style)>,
+}
+
+impl StyledTerminal {
+    /// Forget everything styled so far, for when the lines are cleared.
+    pub fn clear(&mut self) {
+        self.finished.clear();
+    }
+
+    /// The last `count` of `lines`, styled like styled_lines does.
+    pub fn last_lines(&mut self, lines:&[String], count:usize) -> Vec<Line<'static>> {
+        let Some((last,done)) = lines.split_last() else {
+            return Vec::new();
+        };
+        self.finished.truncate(done.len());
+        let mut style = self.finished.last().map_or(Style::default(), |(_,style)| *style);
+        for line in &done[self.finished.len()..] {
+            let (styled,end_style) = style_line(line, style);
+            self.finished.push((styled,end_style));
+            style = end_style;
+        }
+        let mut shown:Vec<Line<'static>> = self.finished[self.finished.len().saturating_sub(count.saturating_sub(1))..].iter()
+            .map(|(line,_)| line.clone())
+            .collect();
+        if count > 0 {
+            shown.push(style_line(last, style).0);
+        }
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Style of each piece of text in `line`.
+    fn spans(line:&Line) -> Vec<(String,Style)> {
+        line.spans.iter().map(|span| (span.content.to_string(),span.style)).collect()
+    }
+
+    #[test]
+    fn empty_parameters_reset() {
+        let bold = Style::default().add_modifier(Modifier::BOLD).fg(Color::Red);
+        assert_eq!(apply_sgr(bold, ""), Style::default());
+        assert_eq!(apply_sgr(bold, "0"), Style::default());
+    }
+
+    #[test]
+    fn combined_parameters() {
+        let style = apply_sgr(Style::default(), "1;4;31;42");
+        assert_eq!(style, Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED).fg(Color::Red).bg(Color::Green));
+        let plain = apply_sgr(style, "22;39;49");
+        assert_eq!((plain.fg,plain.bg,plain.add_modifier), (None,None,Modifier::UNDERLINED));
+    }
+
+    #[test]
+    fn bright_colors() {
+        assert_eq!(apply_sgr(Style::default(), "91;107"), Style::default().fg(Color::LightRed).bg(Color::White));
+        assert_eq!(apply_sgr(Style::default(), "38"), Style::default());
+    }
+
+    #[test]
+    fn style_carries_to_the_next_line() {
+        let lines = [String::from("a\u{1b}[32mb"), String::from("c\u{1b}[mc")];
+        let styled = styled_lines(&lines, 1);
+        assert_eq!(styled.len(), 1);
+        assert_eq!(spans(&styled[0]), [(String::from("c"),Style::default().fg(Color::Green)), (String::from("c"),Style::default())]);
+    }
+
+    #[test]
+    fn unterminated_sequence_is_left_out() {
+        let styled = styled_lines(&[String::from("ok\u{1b}[31")], 1);
+        assert_eq!(spans(&styled[0]), [(String::from("ok"),Style::default())]);
+        assert!(!sequence_complete("\u{1b}[31"));
+        assert!(sequence_complete("\u{1b}[31m"));
+    }
+
+    #[test]
+    fn terminal_styles_the_same_as_all_at_once() {
+        let mut lines = vec![String::from("\u{1b}[1mone"), String::from("two")];
+        let mut terminal = StyledTerminal::default();
+        assert_eq!(terminal.last_lines(&lines, 5), styled_lines(&lines, 5));
+        lines[1].push_str("\u{1b}[0m more");
+        lines.push(String::from("three"));
+        assert_eq!(terminal.last_lines(&lines, 2), styled_lines(&lines, 2));
+        lines.clear();
+        terminal.clear();
+        lines.push(String::from("four"));
+        assert_eq!(terminal.last_lines(&lines, 2), styled_lines(&lines, 2));
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+use std::fs::{File,OpenOptions};
+use std::io::{self,Write};
+use std::time::{SystemTime,UNIX_EPOCH};
+
+/// Length of the codes the challenge hands out.
+const CODE_LENGTH:usize = 12;
+
+/// Watches the output of the VM for anything that looks like a challenge code, and
+/// appends every code it has not seen before to a file. Only meant for what the program
+/// outputs, not for input echoed back to the terminal.
+#[derive(Debug)]
+pub struct CodeCapture {
+    destination:File,
+    seen:HashSet<String>,
+    current_word:String,
+}
+
+impl CodeCapture {
+    /// Open (or create) the capture file. Codes already present in the file are
+    /// remembered, so running the same session twice does not duplicate them.
+    pub fn new(path:&str) -> io::Result<Self> {
+        let seen = match std::fs::read_to_string(path) {
+            Ok(previous) => previous.lines()
+                .filter_map(|line| line.split_whitespace().last())
+                .map(String::from)
+                .collect(),
+            Err(_) => HashSet::new(),
+        };
+        let destination = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { destination, seen, current_word: String::with_capacity(CODE_LENGTH) })
+    }
+
+    /// Feed one character of output into the capture.
+    pub fn push_char(&mut self, ch:char) -> io::Result<()> {
+        if ch.is_ascii_alphanumeric() {
+            self.current_word.push(ch);
+            Ok(())
+        } else {
+            self.finish_word()
+        }
+    }
+
+    /// Take whatever has been output since the last character that can't be part of a
+    /// code as a whole word, for when the output stops without one.
+    pub fn finish_word(&mut self) -> io::Result<()> {
+        let word = std::mem::take(&mut self.current_word);
+        if is_code(&word) && !self.seen.contains(&word) {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0);
+            writeln!(&mut self.destination,"{timestamp} {word}")?;
+            self.seen.insert(word);
+        }
+        Ok(())
+    }
+}
+
+/// Codes are a run of exactly CODE_LENGTH letters and digits. To avoid picking up
+/// long ordinary words, a code also needs a digit or a capital letter past its first
+/// character.
+fn is_code(word:&str) -> bool {
+    word.len() == CODE_LENGTH &&
+        word.chars().skip(1).any(|ch| ch.is_ascii_uppercase() || ch.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `output` to a new capture, end it, and give back the codes that were written.
+    fn captured(name:&str, output:&str) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("synapone-test-{}-{name}",std::process::id()));
+        let path_text = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut capture = CodeCapture::new(path_text).unwrap();
+        for ch in output.chars() {
+            capture.push_char(ch).unwrap();
+        }
+        capture.finish_word().unwrap();
+        let contents = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        contents.unwrap().lines().filter_map(|line| line.split_whitespace().last()).map(String::from).collect()
+    }
+
+    #[test]
+    fn code_at_the_very_end_of_output() {
+        assert_eq!(captured("codes-end.txt", "Your code is: abcDEF123xyz"), ["abcDEF123xyz"]);
+    }
+
+    #[test]
+    fn only_new_codes_that_look_like_codes() {
+        let output = "abcDEF123xyz, extraordinary abcDEF123xyz. ZYXwvu987TSR\n";
+        assert_eq!(captured("codes-seen.txt", output), ["abcDEF123xyz","ZYXwvu987TSR"]);
+    }
+}
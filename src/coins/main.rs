@@ -0,0 +1,96 @@
+use clap::Parser;
+use itertools::Itertools;
+
+/// Solves the coin puzzle: find the order in which to place five coins into the
+/// equation `_ + _ * _^2 + _^3 - _ = target`.
+#[derive(Parser,Debug)]
+#[command(version, about)]
+struct Args{
+    /// A coin and its value, as `name=value`. Give exactly five; defaults to the coins
+    /// found in the challenge.
+    #[arg(short, long, value_parser = parse_coin)]
+    coin:Vec<(String,i64)>,
+
+    /// Result the equation should have.
+    #[arg(short, long, default_value_t = 399)]
+    target:i64,
+}
+
+const DEFAULT_COINS:[(&str,i64);5] = [
+    ("red",2),
+    ("corroded",3),
+    ("shiny",5),
+    ("concave",7),
+    ("blue",9),
+];
+
+fn main() {
+    let args = Args::parse();
+    let coins:Vec<(String,i64)> = if args.coin.is_empty() {
+        DEFAULT_COINS.iter().map(|(name,value)| (String::from(*name),*value)).collect()
+    } else {
+        args.coin
+    };
+    if coins.len() != 5 {
+        eprintln!("Expected exactly five coins, got {}.",coins.len());
+        std::process::exit(1);
+    }
+
+    println!("Coins:");
+    for (name,value) in coins.iter() {
+        println!("  {name:>10} = {value}");
+    }
+
+    let mut found = false;
+    for order in coins.iter().permutations(5) {
+        let values:Vec<i64> = order.iter().map(|(_,value)| *value).collect();
+        if evaluate(&values) == Some(args.target) {
+            found = true;
+            let names = order.iter().map(|(name,_)| &name[..]).join(", ");
+            println!("Insert in order: {names}");
+        }
+    }
+    if !found {
+        println!("No ordering of these coins gives {}.",args.target);
+    }
+}
+
+fn parse_coin(pair:&str) -> Result<(String,i64),String> {
+    let (name,value) = pair.split_once('=').ok_or_else(|| format!("'{pair}' is not of the form name=value"))?;
+    let value = value.trim().parse::<i64>().map_err(|e| format!("'{}' is not a coin value: {e}",value.trim()))?;
+    Ok((String::from(name.trim()),value))
+}
+
+/// Calculate `a + b * c^2 + d^3 - e`, or None if that doesn't fit in an i64.
+fn evaluate(values:&[i64]) -> Option<i64> {
+    let squared = values[2].checked_pow(2)?;
+    let cubed = values[3].checked_pow(3)?;
+    values[1].checked_mul(squared)?
+        .checked_add(values[0])?
+        .checked_add(cubed)?
+        .checked_sub(values[4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_coins_solve_the_puzzle() {
+        //blue, red, shiny, concave, corroded
+        assert_eq!(evaluate(&[9,2,5,7,3]), Some(399));
+    }
+
+    #[test]
+    fn values_too_large_dont_overflow() {
+        assert_eq!(evaluate(&[1,1,1,i64::MAX,1]), None);
+        assert_eq!(evaluate(&[i64::MAX,1,1,1,0]), None);
+    }
+
+    #[test]
+    fn malformed_coins() {
+        assert_eq!(parse_coin(" red = 2"), Ok((String::from("red"),2)));
+        assert!(parse_coin("red").is_err());
+        assert!(parse_coin("red=two").is_err());
+    }
+}
@@ -0,0 +1,9 @@
+/// The lines of a hand-written file that hold something, trimmed. Empty lines and comment
+/// lines, starting with `#` or `;`, are left out. Every line comes with its line number,
+/// counting from 1, for pointing at mistakes.
+pub fn config_lines(contents:&str) -> impl Iterator<Item=(usize,&str)> {
+    contents.lines()
+        .enumerate()
+        .map(|(index,line)| (index + 1, line.trim()))
+        .filter(|(_,line)| !(line.is_empty() || line.starts_with('#') || line.starts_with(';')))
+}
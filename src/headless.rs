@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+use std::io::{self,Write};
+
+use crate::interface::{VmInterface,RuntimeState,ProgramStep,MemoryView};
+
+/// Drives the VM without the TUI, by handing it a fixed list of commands. Output of the
+/// program goes to stdout and errors to stderr. The VM is told to stop once the commands
+/// run out. All input is supplied up front, so there is never more of it to give; what
+/// the program gets when it asks anyway is up to the VM.
+pub struct HeadlessInterface {
+    commands:VecDeque<RuntimeState>,
+}
+
+impl HeadlessInterface {
+    pub fn new(commands:Vec<RuntimeState>) -> Self {
+        Self { commands: commands.into() }
+    }
+}
+
+impl VmInterface for HeadlessInterface {
+    fn write_output(&mut self, c:char) -> io::Result<()> {
+        write!(io::stdout(),"{c}")
+    }
+
+    fn write_steps(&mut self, _steps:Vec<ProgramStep>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_memory_view(&mut self, _view:MemoryView) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn runtime_err(&mut self, message:String) {
+        eprintln!("[VM error] {message}");
+    }
+
+    fn set_finished(&mut self, _finished:bool) {}
+
+    fn set_running(&mut self, _running:bool) {}
+
+    fn set_queued_input(&mut self, _count:usize) {}
+
+    fn read_input(&mut self) -> Vec<u16> {
+        //Nobody to ask.
+        Vec::new()
+    }
+
+    fn input_ended(&self) -> bool {
+        true
+    }
+
+    fn read_state(&mut self, blocking:bool) -> Option<RuntimeState> {
+        if blocking {
+            Some(self.commands.pop_front().unwrap_or(RuntimeState::Terminate))
+        } else {
+            None
+        }
+    }
+}
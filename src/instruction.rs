@@ -90,14 +90,33 @@ impl Display for Operation {
     }
 }
 
+/// What an operand of an operation has to be.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum OperandKind {
+    /// A register the result is stored in.
+    Register,
+    /// A value that is read; either a literal or the contents of a register.
+    Value,
+}
+
 impl Operation {
     pub fn operands(&self) -> u16 {
         match self {
-            Self::Halt | Self::Ret | Self::Noop => 0,
-            Self::Push | Self::Pop | Self::Jmp | Self::Call | Self::Out | Self::In => 1,
-            Self::Set | Self::Jt | Self::Jf | Self::Not | Self::Rmem |Self::Wmem => 2,
-            Self::Eq | Self::Gt | Self::Add | Self::Mult | Self::Mod | Self::And | Self::Or => 3,
             Self::Error(_) => 0xffff,
+            _ => self.operand_spec().len() as u16,
+        }
+    }
+
+    /// The kind of each operand this operation takes, in order.
+    pub fn operand_spec(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Self::Halt | Self::Ret | Self::Noop | Self::Error(_) => &[],
+            Self::Push | Self::Jmp | Self::Call | Self::Out => &[Value],
+            Self::Pop | Self::In => &[Register],
+            Self::Jt | Self::Jf | Self::Wmem => &[Value,Value],
+            Self::Set | Self::Not | Self::Rmem => &[Register,Value],
+            Self::Eq | Self::Gt | Self::Add | Self::Mult | Self::Mod | Self::And | Self::Or => &[Register,Value,Value],
         }
     }
 }
@@ -118,23 +137,67 @@ impl From<u16> for ParsedValue{
     }
 }
 
+/// Registers are padded to the width of a literal, so operands line up in columns. The
+/// alternate form (`{:#}`) leaves out the padding.
 impl Display for ParsedValue{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmtResult {
         match self{
             Self::Literal(v) => write!(f,"{v:04x}"),
+            Self::Register(v) if f.alternate() => write!(f,"R{v}"),
             Self::Register(v) => write!(f,"  R{v}"),
             Self::Error(v) => write!(f,"E({v})"),
         }
     }
 }
 
+/// The opcode of an instruction by its mnemonic, as shown in listings, in any case.
+pub fn opcode_by_name(mnemonic:&str) -> Option<u16> {
+    let mnemonic = mnemonic.trim().to_ascii_uppercase();
+    (0..=21).find(|code| Operation::from(*code).to_string().trim_end() == mnemonic)
+}
+
+/// Turn one line of assembly, such as `ADD R0 R1 5`, into words. Mnemonics are the ones
+/// shown in listings, literals are hexadecimal and registers go from `R0` to `R7`.
+pub fn assemble(line:&str) -> Result<Vec<u16>,String> {
+    let mut parts = line.split_whitespace();
+    let mnemonic = parts.next().ok_or("nothing to assemble")?.to_ascii_uppercase();
+    let opcode = opcode_by_name(&mnemonic)
+        .ok_or_else(|| format!("unknown instruction '{mnemonic}'"))?;
+    let spec = Operation::from(opcode).operand_spec();
+    let operands:Vec<&str> = parts.collect();
+    if operands.len() != spec.len() {
+        return Err(format!("{mnemonic} takes {} operands, got {}",spec.len(),operands.len()));
+    }
+    let mut words = vec![opcode];
+    for (kind,operand) in spec.iter().zip(operands) {
+        words.push(assemble_operand(operand, *kind)?);
+    }
+    Ok(words)
+}
+
+fn assemble_operand(operand:&str, kind:OperandKind) -> Result<u16,String> {
+    if let Some(register) = operand.strip_prefix(['R','r']) {
+        return match register.parse::<u16>() {
+            Ok(index) if index < 8 => Ok(32768 + index),
+            _ => Err(format!("'{operand}' is not a register from R0 to R7")),
+        };
+    }
+    if kind == OperandKind::Register {
+        return Err(format!("expected a register, got '{operand}'"));
+    }
+    match u16::from_str_radix(operand, 16) {
+        Ok(value) if value < 32768 => Ok(value),
+        _ => Err(format!("'{operand}' is not a hexadecimal value from 0 to 7fff")),
+    }
+}
+
 /// Takes in a slice of bytes and, if possible, parses to a vector of
 /// u15's (represented as u16's.)
 pub fn parse_program_slice(input:&[u8]) -> Result<Vec<u16>,()> {
-    if input.len() % 2 != 0 {
+    if !input.len().is_multiple_of(2) {
         return Err(());
     }
-    let retval = input.into_iter()
+    let retval = input.iter()
         .tuples::<(&u8,&u8)>()
         .map(|(low,high)|{
             u16::from_le_bytes([*low,*high])
@@ -142,3 +205,43 @@ pub fn parse_program_slice(input:&[u8]) -> Result<Vec<u16>,()> {
         .collect();
     Ok(retval)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::OperandKind::*;
+
+    #[test]
+    fn operand_spec_of_every_opcode() {
+        let expected:[&[OperandKind];22] = [
+            &[],                        //halt
+            &[Register,Value],          //set
+            &[Value],                   //push
+            &[Register],                //pop
+            &[Register,Value,Value],    //eq
+            &[Register,Value,Value],    //gt
+            &[Value],                   //jmp
+            &[Value,Value],             //jt
+            &[Value,Value],             //jf
+            &[Register,Value,Value],    //add
+            &[Register,Value,Value],    //mult
+            &[Register,Value,Value],    //mod
+            &[Register,Value,Value],    //and
+            &[Register,Value,Value],    //or
+            &[Register,Value],          //not
+            &[Register,Value],          //rmem
+            &[Value,Value],             //wmem
+            &[Value],                   //call
+            &[],                        //ret
+            &[Value],                   //out
+            &[Register],                //in
+            &[],                        //noop
+        ];
+        for (opcode,spec) in expected.iter().enumerate() {
+            let operation = Operation::from(opcode as u16);
+            assert_eq!(operation.operand_spec(), *spec, "operands of {operation}");
+            assert_eq!(operation.operands() as usize, spec.len(), "operand count of {operation}");
+        }
+        assert_eq!(Operation::from(22).operand_spec(), &[]);
+    }
+}
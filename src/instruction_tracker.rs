@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{BufWriter,Write,Result as IoResult};
+
+use crate::instruction::{Operation,ParsedValue};
+use crate::interface::RegisterState;
+
+/// Writes a trace of every control-flow change and memory access the VM makes, one
+/// line per instruction in the form `<type> <pc> <address>`. The type is one of:
+/// - `J`: unconditional jump to the address.
+/// - `T`: conditional branch that was taken, to the address.
+/// - `B`: conditional branch that was not taken; the address is where execution continues.
+/// - `C`: call of the subroutine at the address.
+/// - `R`: return to the address.
+/// - `L`: memory load from the address.
+/// - `S`: memory store to the address.
+///
+/// Notes from the user show up in between as `---- note: <text> ----`.
+///
+/// In the CSV format every instruction gets a line instead, see TraceFormat::Csv.
+pub struct InstructionTracker {
+    destination:BufWriter<File>,
+    format:TraceFormat,
+    /// Instructions recorded so far.
+    count:usize,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TraceFormat {
+    /// Only control flow and memory access, as described for InstructionTracker.
+    Compact,
+    /// Every instruction, under the header `index,pc,mnemonic,a,b,c`: how many instructions
+    /// came before it in the trace, its address, its name and the values of its operands,
+    /// with registers replaced by what they held. All numbers are decimal, and operands an
+    /// instruction doesn't have are left empty. Notes are lines starting with `#`.
+    Csv,
+}
+
+impl InstructionTracker {
+    pub fn new(save_location:&str, format:TraceFormat) -> IoResult<Self> {
+        let destination_file = File::create(save_location)?;
+        let mut destination = BufWriter::new(destination_file);
+        if format == TraceFormat::Csv {
+            writeln!(&mut destination,"index,pc,mnemonic,a,b,c")?;
+        }
+        Ok(Self { destination, format, count: 0 })
+    }
+
+    /// Record a single executed instruction. `registers` is the state from before the
+    /// instruction executed, `next_pc` the program counter after it did.
+    pub fn instruction(&mut self, pc:u16, op:&Operation, operands:&[ParsedValue], registers:&RegisterState, next_pc:u16) -> IoResult<()> {
+        let resolve = |value:&ParsedValue| match value {
+            ParsedValue::Literal(v) => *v,
+            ParsedValue::Register(r) => registers.registers[*r as usize],
+            ParsedValue::Error(e) => *e,
+        };
+        if self.format == TraceFormat::Csv {
+            let mut line = format!("{},{pc},{}",self.count,op.to_string().trim_end());
+            for index in 0..3 {
+                line.push(',');
+                if let Some(value) = operands.get(index) {
+                    line.push_str(&resolve(value).to_string());
+                }
+            }
+            self.count += 1;
+            return writeln!(&mut self.destination,"{line}");
+        }
+        let (op_type, op_addr) = match op {
+            Operation::Jmp => ('J', next_pc),
+            Operation::Jt | Operation::Jf => {
+                let condition = resolve(&operands[0]) != 0;
+                if condition == (*op == Operation::Jt) {
+                    ('T', next_pc)
+                } else {
+                    ('B', next_pc)
+                }
+            },
+            Operation::Call => ('C', next_pc),
+            Operation::Ret => ('R', next_pc),
+            Operation::Rmem => ('L', resolve(&operands[1])),
+            Operation::Wmem => ('S', resolve(&operands[0])),
+            _ => return Ok(()),
+        };
+        writeln!(&mut self.destination,"{op_type} {pc:04x} {op_addr:04x}")
+    }
+
+    /// Write a note, to find this point in the trace again later.
+    pub fn mark(&mut self, note:&str) -> IoResult<()> {
+        match self.format {
+            TraceFormat::Compact => writeln!(&mut self.destination,"---- note: {note} ----"),
+            TraceFormat::Csv => writeln!(&mut self.destination,"# {note}"),
+        }
+    }
+}
+
+impl Drop for InstructionTracker {
+    fn drop(&mut self) {
+        //Nothing sensible left to do with an error at this point.
+        let _ = self.destination.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interface::RuntimeState;
+    use crate::machine::VirtualMachine;
+    use crate::test_interface::TestInterface;
+
+    //JMP 2, CALL 8, JT 1 9, HALT, RET, HALT
+    const PROGRAM:[u16;10] = [6,2, 17,8, 7,1,9, 0, 18, 0];
+
+    /// Trace PROGRAM until it halts, in the CSV format if `csv`, and give back the lines of
+    /// the trace.
+    fn trace(name:&str, csv:bool) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("synapone-test-{}-{name}",std::process::id()));
+        let path_text = path.to_str().unwrap().to_string();
+        let mut vm = VirtualMachine::init_from_sequence(&PROGRAM);
+        let start = if csv {RuntimeState::TraceOperationsCsv(path_text)} else {RuntimeState::TraceOperations(path_text)};
+        let mut interface = TestInterface::new(vec![start, RuntimeState::TraceMark(String::from("start")), RuntimeState::Run, RuntimeState::TraceStop]);
+        vm.run_program(&mut interface);
+        let contents = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        contents.unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn compact_trace_of_jumps() {
+        assert_eq!(trace("trace.txt", false), [
+            "---- note: start ----",
+            "J 0000 0002",
+            "C 0002 0008",
+            "R 0008 0004",
+            "T 0004 0009",
+        ]);
+    }
+
+    #[test]
+    fn csv_trace_of_every_instruction() {
+        assert_eq!(trace("trace.csv", true), [
+            "index,pc,mnemonic,a,b,c",
+            "# start",
+            "0,0,JMP,2,,",
+            "1,2,CALL,8,,",
+            "2,8,RET,,,",
+            "3,4,JT,1,9,",
+        ]);
+    }
+}
@@ -4,29 +4,67 @@ pub enum RuntimeState {
     Run,
     Pause,
     SingleStep,
+    /// Like SingleStep, except that a CALL runs until it returns to the next instruction.
+    StepOver,
     RunForSteps(usize),
     RunUntilAddress(u16),
+    /// Run until the routine being executed returns to whatever called it.
+    StepOut,
+    /// Run until a RET leaves fewer than this many values on the stack.
+    RunUntilReturn(usize),
+    /// Run until the program has just output this text. Only the last RECENT_OUTPUT_LEN
+    /// bytes of output are kept, so longer text never matches.
+    RunUntilOutputContains(String),
     SetCommandDelay(usize,bool),
+    ProfileStart,
+    ProfileStop(String),
+    TraceOperations(String),
+    /// Trace every instruction as a line of CSV, to load into a spreadsheet.
+    TraceOperationsCsv(String),
+    TraceStop,
+    /// Write a note into the trace, if one is being written.
+    TraceMark(String),
+    LogOutput(String),
+    StopLogOutput,
+    Reset,
+    /// Keep a copy of the state of the VM in a numbered slot, to go back to with QuickLoad.
+    QuickSave(u8),
+    QuickLoad(u8),
+    AnalyzeNow(String),
+    /// Dump the words from the first address up to and including the second to a file.
+    DumpMemory(u16,u16,String),
+    /// Save memory as it is now as a program binary, to load again later.
+    SaveBinary(String),
+    SetMemory(u16,u16),
+    UndoEdit,
+    /// Execute these words as an instruction, without putting them in memory.
+    Inject(Vec<u16>),
+    /// Pause whenever the instruction about to be executed covers this address.
+    AddBreakpoint(u16),
+    RemoveBreakpoint(u16),
+    ClearBreakpoints,
+    /// Replace all breakpoints with these, such as ones loaded from a file.
+    SetBreakpoints(Vec<u16>),
+    /// Pause right after executing any instruction with this opcode.
+    BreakOnOpcode(u16),
+    RemoveOpcodeBreak(u16),
+    ClearOpcodeBreaks,
     Terminate,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct RegisterState {
     pub registers:[u16;8],
     pub stack_depth:usize,
     pub program_counter:u16,
 }
 
-impl Default for RegisterState {
-    fn default() -> Self {
-        Self { registers: Default::default(), stack_depth: Default::default(), program_counter: Default::default() }
-    }
-}
-
 #[derive(Debug,Default,Clone)]
 pub struct ProgramStep {
     pub registers:RegisterState,
     pub instruction:String,
+    /// Number of characters the program had output before this step.
+    pub output_len:usize,
 }
 
 impl ProgramStep {
@@ -37,29 +75,77 @@ impl ProgramStep {
                 stack_depth: 0,
                 program_counter: 0
             },
-            instruction : String::new()
+            instruction : String::new(),
+            output_len : 0
         }
     }
 
-    pub fn step(registers:RegisterState, instruction:String) -> Self {
-        Self { registers: registers, instruction: instruction }
+    pub fn step(registers:RegisterState, instruction:String, output_len:usize) -> Self {
+        Self { registers, instruction, output_len }
+    }
+}
+
+/// A copy of a stretch of VM memory, starting at the given address.
+#[derive(Debug,Default,Clone)]
+pub struct MemoryView {
+    pub start:u16,
+    pub words:Vec<u16>,
+    /// The registers when the copy was made, to tell what register operands hold.
+    pub registers:[u16;8],
+    /// Return addresses on the stack, from the innermost call out.
+    pub call_stack:Vec<u16>,
+}
+
+/// Turn text into words of VM input. Only ASCII characters are kept.
+pub fn text_input(text:&str) -> Vec<u16> {
+    text.chars()
+        .filter(|ch| ch.is_ascii())
+        .map(|ch| (ch as u64 & 0x7f) as u16)
+        .collect()
+}
+
+/// Start tracing to `path`, as CSV if the file name ends in `.csv`.
+pub fn trace_to(path:String) -> RuntimeState {
+    if path.to_ascii_lowercase().ends_with(".csv") {
+        RuntimeState::TraceOperationsCsv(path)
+    } else {
+        RuntimeState::TraceOperations(path)
     }
 }
 
 pub trait UiInterface {
     fn read_output(&mut self) -> Option<String>;
     fn read_steps(&mut self) -> Vec<ProgramStep>;
+    fn read_errors(&mut self) -> Vec<String>;
+    fn read_memory_view(&mut self) -> Option<MemoryView>;
     fn need_input(&self) -> bool;
     fn is_finished(&self) -> bool;
+    /// Whether the VM is executing instructions, rather than waiting for a command.
+    fn is_running(&self) -> bool;
+    /// Number of input characters the VM has, waiting to be read by the program.
+    fn queued_input(&self) -> usize;
     fn write_input(&mut self, input:&str) -> std::io::Result<()>;
+    /// Send input to the VM exactly as given, without leaving out any values.
+    fn write_input_bytes(&mut self, input:&[u8]) -> std::io::Result<()>;
     fn write_state(&mut self, input:RuntimeState) -> std::io::Result<()>;
 }
 
 pub trait VmInterface {
     fn write_output(&mut self, c:char) -> std::io::Result<()>;
-    fn write_step(&mut self, step:ProgramStep) -> std::io::Result<()>;
+    /// Send a batch of executed steps, oldest first.
+    fn write_steps(&mut self, steps:Vec<ProgramStep>) -> std::io::Result<()>;
+    fn write_memory_view(&mut self, view:MemoryView) -> std::io::Result<()>;
     fn runtime_err(&mut self, message:String);
-    fn read_input(&mut self) -> String;
+    /// Let the UI know whether the program has run to its end.
+    fn set_finished(&mut self, finished:bool);
+    /// Let the UI know whether instructions are being executed right now.
+    fn set_running(&mut self, running:bool);
+    /// Let the UI know how many input characters are waiting to be read by the program.
+    fn set_queued_input(&mut self, count:usize);
+    fn read_input(&mut self) -> Vec<u16>;
+    /// Whether read_input has nothing more to give, now or ever, such as when a script has
+    /// been read in full and there is nobody to type more.
+    fn input_ended(&self) -> bool;
     fn read_state(&mut self, blocking:bool) -> Option<RuntimeState>;
 }
 
@@ -1,31 +1,137 @@
-use std::io::{BufReader,Read,BufWriter,Write,Result as io_result};
-use std::fs::File;
+use std::io::{BufWriter,Write,Result as io_result};
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::hash::{DefaultHasher,Hash,Hasher};
+use std::fs::{File,OpenOptions};
 use std::convert::From;
 use std::fmt::{Display,Result as fmtResult};
 use itertools::Itertools;
-use crate::interface::{VmInterface,RuntimeState,RegisterState,ProgramStep};
-use crate::instruction::{Operation,ParsedValue};
+use crate::interface::{VmInterface,RuntimeState,RegisterState,ProgramStep,MemoryView,text_input};
+use crate::instruction::{Operation,OperandKind,ParsedValue,parse_program_slice};
+use crate::instruction_tracker::{InstructionTracker,TraceFormat};
+use crate::static_analysis::{parse_program_and_save,analyze,OutputFormat,AnalysisError};
 
+#[derive(Clone)]
 pub struct VirtualMachine {
     memory:Vec<u16>,
+    /// Copy of the memory as loaded, to reset to.
+    initial_memory:Option<Vec<u16>>,
     registers:[u16;8],
     stack:Vec<usize>,
+    /// Most values the stack may hold, if limited.
+    max_stack_depth:Option<usize>,
     program_counter:usize,
-    input_buffer:Vec<u16>,
+    /// Input not yet read by the program, first in first out.
+    input_buffer:VecDeque<u16>,
+    loop_threshold:usize,
+    /// Pause on runtime errors, rather than carrying on.
+    pause_on_error:bool,
+    /// Treat output of anything but 7-bit ASCII as an error.
+    strict_output:bool,
+    /// Every piece of input is made to end in a line-feed, so lines never span two of them.
+    line_input:bool,
+    /// Once input has run out for good, IN reads 0 instead of stopping the program.
+    zero_at_end_of_input:bool,
+    profile:Option<HashMap<u16,u64>>,
+    /// The last RECENT_OUTPUT_LEN bytes the program output, oldest first.
+    recent_output:VecDeque<u8>,
 }
 
+/// Number of words from the program counter onward sent to the UI when pausing; enough
+/// for eight instructions with three operands each.
+const PREVIEW_WORDS:usize = 32;
+
+/// Number of executed steps collected before sending them to the UI in one go, when
+/// running without a delay.
+const STEP_BATCH:usize = 256;
+
+/// Number of output bytes kept to look for text in; text waited for with
+/// RunUntilOutputContains can't be longer than this.
+const RECENT_OUTPUT_LEN:usize = 256;
+
+/// Number of words a program can address; literal values only go up to 32767. Programs
+/// can't be larger than this and memory never grows past it, so the program counter
+/// always fits in 16 bits.
+pub const ADDRESS_SPACE:usize = 0x8000;
+
+/// How many manual changes to memory can be undone.
+const EDIT_HISTORY_LEN:usize = 32;
+
+/// How many of the most-executed addresses a profile lists.
+const PROFILE_TOP_N:usize = 50;
+
+/// Upper bound on the number of distinct states the loop detector remembers; past this,
+/// it forgets everything and starts over, to keep long computations from eating memory.
+const LOOP_DETECTOR_CAPACITY:usize = 1 << 16;
+
+/// Keeps track of how often each (program counter, registers, stack depth) combination
+/// came up since the last output.
+struct LoopDetector {
+    threshold:usize,
+    seen:HashMap<u64,usize>,
+}
+
+impl LoopDetector {
+    fn new(threshold:usize) -> Self {
+        Self { threshold, seen: HashMap::new() }
+    }
+
+    fn reset(&mut self) {
+        self.seen.clear();
+    }
+
+    /// Record a state, returning true if it recurred more often than the threshold.
+    fn is_stuck(&mut self, state:&RegisterState) -> bool {
+        if self.seen.len() >= LOOP_DETECTOR_CAPACITY {
+            self.seen.clear();
+        }
+        let mut hasher = DefaultHasher::new();
+        (state.program_counter, state.registers, state.stack_depth).hash(&mut hasher);
+        let count = self.seen.entry(hasher.finish()).or_insert(0);
+        *count += 1;
+        if *count > self.threshold {
+            self.seen.clear();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Number of quick save slots.
+pub const SAVE_SLOTS:usize = 10;
+
+/// Everything about a running program needed to go back to a moment in it. Memory makes up
+/// most of it, ADDRESS_SPACE words or 64 KiB, so all SAVE_SLOTS slots filled hold about
+/// 640 KiB, plus whatever is on the stack and waiting in the input buffer.
+struct Snapshot {
+    memory:Vec<u16>,
+    registers:[u16;8],
+    stack:Vec<usize>,
+    program_counter:usize,
+    input_buffer:VecDeque<u16>,
+}
+
+#[allow(dead_code)]
 pub struct VirtualMachineStep<'a> {
     machine:&'a mut VirtualMachine,
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum RuntimeError {
     ErrFinished,
     ErrUnknownOperation(u16),
     ErrUnknownOperand(u16),
     ErrRegisterExpected,
     ErrInputEmpty,
+    /// IN with nothing left to read, and no more input to come.
+    ErrInputEOF,
     ErrStackEmpty,
+    ErrTruncatedInstruction(u16),
+    ErrAddressOutOfRange(u16),
+    ErrNonAsciiOutput(u16),
+    ErrStackOverflow(usize),
+    ErrDivisionByZero,
 }
 
 impl Display for RuntimeError {
@@ -36,61 +142,285 @@ impl Display for RuntimeError {
             RuntimeError::ErrUnknownOperand(x) => format!("Unknown operand with value {x:x}."),
             RuntimeError::ErrRegisterExpected => String::from("Expected a register, got a literal value."),
             RuntimeError::ErrStackEmpty => String::from("POP instruction executed with empty stack."),
-            RuntimeError::ErrInputEmpty => String::from("IN instruction executed while input buffer was empty.")
+            RuntimeError::ErrInputEmpty => String::from("IN instruction executed while input buffer was empty."),
+            RuntimeError::ErrInputEOF => String::from("IN instruction executed after all input was used up."),
+            RuntimeError::ErrTruncatedInstruction(x) => format!("Instruction at {x:04x} runs past the end of memory."),
+            RuntimeError::ErrAddressOutOfRange(x) => format!("Address {x:04x} is outside the {ADDRESS_SPACE}-word address space."),
+            RuntimeError::ErrNonAsciiOutput(x) => format!("OUT instruction with value {x:04x}, which is not 7-bit ASCII."),
+            RuntimeError::ErrStackOverflow(x) => format!("Stack is already {x} values deep, the most it may hold."),
+            RuntimeError::ErrDivisionByZero => String::from("MOD instruction with a divisor of 0."),
         };
         write!(f,"{message}" )
     }
 }
 
+/// A Fletcher-32 checksum over some words of memory.
+fn checksum(words:&[u16]) -> u32 {
+    let (low,high) = words.iter().fold((0u32,0u32), |(low,high),word| {
+        let low = (low + *word as u32) % 0xffff;
+        (low, (high + low) % 0xffff)
+    });
+    (high << 16) | low
+}
+
+/// File that dump_memory_raw keeps the checksum of a saved binary in.
+fn checksum_path(binary_path:&str) -> String {
+    format!("{binary_path}.sum")
+}
+
 impl VirtualMachine {
+    /// Load a program from a binary file of little-endian words. A file with an odd number
+    /// of bytes is refused, since it was most likely cut short, and so is one that doesn't
+    /// match the checksum saved next to it by dump_memory_raw, if there is one.
     pub fn init_from_file(file_path:&str) -> Result<Self,std::io::Error> {
-        let source_file = File::open(file_path)?;
-        let buffer = BufReader::new(source_file);
-        let data_buffer:Vec<u16> = buffer.bytes()
-        .into_iter()
-        .map(|x|x.unwrap_or(0))
-        .tuples::<(u8,u8)>()
-        .map(|(low,hi)|{
-            let mut retval:u16 = hi as u16;
-            retval <<= 8;
-            retval |= low as u16;
-            retval
-        })
-        .collect();
-        Ok(VirtualMachine{
-            memory : data_buffer,
-            registers : [0;8],
-            stack : Vec::<usize>::new(),
-            program_counter : 0,
-            input_buffer : Vec::with_capacity(32)
-        })
+        let bytes = std::fs::read(file_path)?;
+        let data_buffer = parse_program_slice(&bytes).map_err(|_| std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{file_path} is {} bytes long, which is not a whole number of 16-bit words; the file may be truncated", bytes.len())
+        ))?;
+        if data_buffer.len() > ADDRESS_SPACE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{file_path} holds {} words, more than the {ADDRESS_SPACE} words a program can address", data_buffer.len())
+            ));
+        }
+        if let Ok(saved) = std::fs::read_to_string(checksum_path(file_path)) {
+            let expected = u32::from_str_radix(saved.trim(), 16).map_err(|e| std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not a checksum: {e}", checksum_path(file_path))
+            ))?;
+            let actual = checksum(&data_buffer);
+            if actual != expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{file_path} has checksum {actual:08x} instead of the {expected:08x} it was saved with; the file may be corrupted")
+                ));
+            }
+        }
+        Ok(Self::from_memory(data_buffer))
     }
 
     pub fn init_from_sequence(input_sequence:&[u16]) -> Self {
+        Self::from_memory(Vec::from_iter(input_sequence.iter().copied()))
+    }
+
+    fn from_memory(memory:Vec<u16>) -> Self {
         VirtualMachine{
-            memory : Vec::from_iter(input_sequence.iter().map(|x|*x)),
+            initial_memory : Some(memory.clone()),
+            memory,
             registers : [0;8],
             stack : Vec::<usize>::new(),
+            max_stack_depth : None,
             program_counter : 0,
-            input_buffer : Vec::with_capacity(32)
+            input_buffer : VecDeque::with_capacity(32),
+            loop_threshold : 0,
+            pause_on_error : false,
+            strict_output : false,
+            line_input : false,
+            zero_at_end_of_input : false,
+            profile : None,
+            recent_output : VecDeque::with_capacity(RECENT_OUTPUT_LEN),
+        }
+    }
+
+    /// Drop the copy of the initial memory kept around for resets. That copy is as large
+    /// as the loaded program (64KiB for the challenge binary); without it, the VM can
+    /// no longer be reset.
+    pub fn discard_initial_memory(&mut self) {
+        self.initial_memory = None;
+    }
+
+    /// Put the VM back in the state it was in right after loading: the initial memory,
+    /// all registers zero, an empty stack and input buffer, and execution at address 0.
+    /// Returns false (and changes nothing) if the initial memory was discarded.
+    pub fn reset(&mut self) -> bool {
+        let Some(initial) = &self.initial_memory else {
+            return false;
+        };
+        self.memory.clone_from(initial);
+        self.registers = [0;8];
+        self.stack.clear();
+        self.program_counter = 0;
+        self.input_buffer.clear();
+        self.recent_output.clear();
+        true
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.clone(),
+            registers: self.registers,
+            stack: self.stack.clone(),
+            program_counter: self.program_counter,
+            input_buffer: self.input_buffer.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot:&Snapshot) {
+        self.memory.clone_from(&snapshot.memory);
+        self.registers = snapshot.registers;
+        self.stack.clone_from(&snapshot.stack);
+        self.program_counter = snapshot.program_counter;
+        self.input_buffer.clone_from(&snapshot.input_buffer);
+        self.recent_output.clear();
+    }
+
+    /// Set how often the same state may recur without output before execution is paused
+    /// as a likely infinite loop. The check hashes the state after every instruction, so
+    /// it is off unless asked for; a threshold of 0, the default, disables it.
+    pub fn set_loop_threshold(&mut self, threshold:usize) {
+        self.loop_threshold = threshold;
+    }
+
+    /// Limit how many values the stack may hold. A CALL or PUSH that would go past the limit
+    /// is an error that pauses the VM at that instruction, instead of letting runaway
+    /// recursion eat all available memory. None, the default, leaves the stack unlimited.
+    pub fn set_max_stack_depth(&mut self, depth:Option<usize>) {
+        self.max_stack_depth = depth;
+    }
+
+    /// Pause on runtime errors, with the offending instruction as the last step, instead of
+    /// skipping past the error.
+    pub fn set_pause_on_error(&mut self, pause:bool) {
+        self.pause_on_error = pause;
+    }
+
+    /// Report OUT instructions with a value above 0x7f as errors, instead of printing a
+    /// replacement character for them. Synacor programs only print 7-bit ASCII, so such a
+    /// value points at a routine printing garbage.
+    pub fn set_strict_output(&mut self, strict:bool) {
+        self.strict_output = strict;
+    }
+
+    /// Finish every piece of input with a line-feed if it doesn't end in one. New input
+    /// only arrives once everything before it has been read, so this way a line the
+    /// program has started reading can't be finished by input from somewhere else, like
+    /// a typed command tacked onto an unfinished line of raw input or of a script.
+    pub fn set_line_input(&mut self, whole_lines:bool) {
+        self.line_input = whole_lines;
+    }
+
+    /// Decide what IN does when the interface has no input left and never will: read 0, or
+    /// stop the program with ErrInputEOF.
+    pub fn set_zero_at_end_of_input(&mut self, zero:bool) {
+        self.zero_at_end_of_input = zero;
+    }
+
+    /// The full contents of the VM's memory.
+    pub fn memory(&self) -> &[u16] {
+        &self.memory
+    }
+
+    /// The word at the given address, or 0 if the address lies past the end of memory.
+    pub fn word_at(&self, addr:u16) -> u16 {
+        self.memory.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    /// A Fletcher-32 checksum over all of memory, to tell two memory images apart quickly.
+    pub fn memory_checksum(&self) -> u32 {
+        checksum(&self.memory)
+    }
+
+    /// Overwrite the word at the given address, growing memory if it is too short. Fails
+    /// for addresses outside the address space.
+    pub fn set_word(&mut self, addr:u16, value:u16) -> Result<(),RuntimeError> {
+        if addr as usize >= ADDRESS_SPACE {
+            return Err(RuntimeError::ErrAddressOutOfRange(addr));
+        }
+        if self.memory.len() <= addr as usize {
+            self.memory.resize(addr as usize + 1, 0);
+        }
+        self.memory[addr as usize] = value;
+        Ok(())
+    }
+
+    /// Copy up to `length` words of memory, starting at `start`.
+    pub fn memory_view(&self, start:u16, length:usize) -> MemoryView {
+        let start_index = start as usize;
+        let end = self.memory.len().min(start_index + length);
+        MemoryView {
+            start,
+            words: self.memory.get(start_index..end).map(Vec::from).unwrap_or_default(),
+            registers: self.registers,
+            call_stack: self.return_addresses(),
+        }
+    }
+
+    /// The values on the stack that are return addresses, innermost first. The stack holds
+    /// pushed values as well, so only those right after a CALL instruction count.
+    fn return_addresses(&self) -> Vec<u16> {
+        self.stack.iter().rev()
+            .filter(|address| address.checked_sub(2)
+                .and_then(|call| self.memory.get(call))
+                .is_some_and(|word| Operation::from(*word) == Operation::Call))
+            .filter_map(|address| u16::try_from(*address).ok())
+            .collect()
+    }
+
+    /// The program counter as an address. Jump targets are 16-bit and memory is never
+    /// larger than ADDRESS_SPACE, so it always fits; running off the end of memory is
+    /// caught before the counter can go any further.
+    fn pc(&self) -> u16 {
+        u16::try_from(self.program_counter).expect("program counter outside of the address space")
+    }
+
+    /// Queue a script of input for the program to consume, before any input that is
+    /// entered interactively. Every line is terminated with a line-feed, including the
+    /// last one if the script did not end in one.
+    pub fn prefill_input(&mut self, script:&str) {
+        let mut input = String::with_capacity(script.len() + 1);
+        for line in script.lines() {
+            input.push_str(line);
+            input.push('\n');
         }
+        self.push_input(&text_input(&input));
     }
 
-    fn dereference(&self,val:&ParsedValue) -> u16 {
+    /// Queue the contents of a script file as input, the way prefill_input does.
+    pub fn prefill_input_from_file(&mut self, script_path:&str) -> io_result<()> {
+        let script = std::fs::read_to_string(script_path)?;
+        self.prefill_input(&script);
+        Ok(())
+    }
+
+    /// Add input to the end of the input buffer, after anything still waiting to be read.
+    fn push_input(&mut self, input:&[u16]) {
+        self.input_buffer.extend(input);
+        if self.line_input && input.last().is_some_and(|last| *last != u16::from(b'\n')) {
+            self.input_buffer.push_back(u16::from(b'\n'));
+        }
+    }
+
+    fn push_stack(&mut self, value:usize) -> Result<(),RuntimeError> {
+        if self.max_stack_depth.is_some_and(|limit| self.stack.len() >= limit) {
+            return Err(RuntimeError::ErrStackOverflow(self.stack.len()));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// The value an operand stands for. Operand words past the last register are an
+    /// error, rather than being masked into something that looks like a valid literal.
+    fn dereference(&self,val:&ParsedValue) -> Result<u16,RuntimeError> {
         match val {
-            ParsedValue::Literal(x) => *x,
-            ParsedValue::Register(r) => self.registers[*r as usize].clone(),
-            ParsedValue::Error(e) => e & 0x7FFF,
+            ParsedValue::Literal(x) => Ok(*x),
+            ParsedValue::Register(r) => Ok(self.registers[*r as usize]),
+            ParsedValue::Error(e) => Err(RuntimeError::ErrUnknownOperand(*e)),
         }
     }
 
     pub fn operation(&mut self) -> Result<(Operation,Vec<ParsedValue>,Option<char>),RuntimeError> {
-        let mut to_print = None;
         //fetch
-        let current_instruction = Operation::from(self.memory[self.program_counter]);
         let old_count = self.program_counter;
+        let current_instruction = Operation::from(self.word_at(self.pc()));
         //decode
-        let argcount = current_instruction.operands() as usize;
+        let argcount = match current_instruction {
+            Operation::Error(_) => 0,
+            _ => current_instruction.operands() as usize,
+        };
+        if old_count + argcount >= self.memory.len() {
+            return Err(RuntimeError::ErrTruncatedInstruction(self.pc()));
+        }
         let mut operands:Vec<ParsedValue> = Vec::with_capacity(argcount);
         for x in old_count+1..old_count+1+argcount {
             let pv = ParsedValue::from(self.memory[x]);
@@ -102,213 +432,186 @@ impl VirtualMachine {
         }
         //Update program counter here, so that jumping instructions can still overwrite it.
         self.program_counter += argcount+1;
-        //execute, store
+        self.execute(current_instruction, operands, old_count)
+    }
+
+    /// Execute an instruction given as words, instead of the one in memory at the program
+    /// counter. Registers, stack and memory change as usual, but the program counter only
+    /// moves if the instruction jumps.
+    pub fn inject(&mut self, words:&[u16]) -> Result<(Operation,Vec<ParsedValue>,Option<char>),RuntimeError> {
+        let current_instruction = Operation::from(words.first().copied().unwrap_or(0));
+        let argcount = match current_instruction {
+            Operation::Error(_) => 0,
+            _ => current_instruction.operands() as usize,
+        };
+        let Some(operand_words) = words.get(1..1+argcount) else {
+            return Err(RuntimeError::ErrTruncatedInstruction(self.pc()));
+        };
+        let mut operands:Vec<ParsedValue> = Vec::with_capacity(argcount);
+        for word in operand_words {
+            match ParsedValue::from(*word) {
+                ParsedValue::Error(x) => return Err(RuntimeError::ErrUnknownOperand(x)),
+                pv => operands.push(pv),
+            }
+        }
+        self.execute(current_instruction, operands, self.program_counter)
+    }
+
+    /// Carry out a decoded instruction. `old_count` is where the program counter goes back
+    /// to when the instruction has to wait for input.
+    fn execute(&mut self, current_instruction:Operation, operands:Vec<ParsedValue>, old_count:usize) -> Result<(Operation,Vec<ParsedValue>,Option<char>),RuntimeError> {
+        let mut to_print = None;
+        check_operands(&current_instruction, &operands)?;
+        //execute, store. Operands were checked above, so every operand that should be a
+        //register is one.
         match current_instruction {
             Operation::Halt => {
                 self.program_counter = old_count;
                 return Err(RuntimeError::ErrFinished)
             },
             Operation::Set => {
-                match operands[0] {
-                    ParsedValue::Literal(_) => return Err(RuntimeError::ErrRegisterExpected),
-                    ParsedValue::Register(r) => {
-                        let val_b = self.dereference(&operands[1]);
-                        self.registers[r as usize] = val_b;
-                    },
-                    ParsedValue::Error(e) => {
-                        return Err(RuntimeError::ErrUnknownOperand(e));
-                    }
-                }
+                let a = register_index(&operands[0]);
+                self.registers[a] = self.dereference(&operands[1])?;
             },
             Operation::Push => {
-                match operands[0] {
-                    ParsedValue::Literal(l) => {
-                        self.stack.push(l.into());
-                    }
-                    ParsedValue::Register(r) => {
-                        self.stack.push(self.registers[r as usize].into());
-                    }
-                    ParsedValue::Error(e) => {
-                        return Err(RuntimeError::ErrUnknownOperand(e));
-                    }
+                let a = self.dereference(&operands[0])?;
+                if let Err(e) = self.push_stack(a.into()) {
+                    //Not executed, so it is the one to look at when pausing.
+                    self.program_counter = old_count;
+                    return Err(e);
                 }
             },
             Operation::Pop => {
-                match operands[0] {
-                    ParsedValue::Register(r) => {
-                        let popped = self.stack.pop();
-                        if let Some(val) = popped {
-                            self.registers[r as usize] = (val & 0x7fff) as u16;
-                        } else {
-                            return Err(RuntimeError::ErrStackEmpty);
-                        }
-                    },
-                    ParsedValue::Literal(v) => return Err(RuntimeError::ErrUnknownOperand(v)),
-                    ParsedValue::Error(v) => return Err(RuntimeError::ErrUnknownOperand(v))
+                let a = register_index(&operands[0]);
+                if let Some(val) = self.stack.pop() {
+                    self.registers[a] = (val & 0x7fff) as u16;
+                } else {
+                    return Err(RuntimeError::ErrStackEmpty);
                 }
             },
             Operation::Eq => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let c = self.dereference(&operands[2]);
-                    if b == c {
-                        self.registers[a as usize] = 1;
-                    } else {
-                        self.registers[a as usize] = 0;
-                    }
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                let c = self.dereference(&operands[2])?;
+                self.registers[a] = if b == c {1} else {0};
             },
             Operation::Gt => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let c = self.dereference(&operands[2]);
-                    if b > c {
-                        self.registers[a as usize] = 1;
-                    } else {
-                        self.registers[a as usize] = 0;
-                    }
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                let c = self.dereference(&operands[2])?;
+                self.registers[a] = if b > c {1} else {0};
             },
             Operation::Jmp => {
-                self.program_counter = self.dereference(&operands[0]) as usize;
+                self.program_counter = self.dereference(&operands[0])? as usize;
             },
             Operation::Jt => {
-                if self.dereference(&operands[0]) != 0 {
-                    self.program_counter = self.dereference(&operands[1]) as usize;
+                if self.dereference(&operands[0])? != 0 {
+                    self.program_counter = self.dereference(&operands[1])? as usize;
                 }
             },
             Operation::Jf => {
-                if self.dereference(&operands[0]) == 0 {
-                    self.program_counter = self.dereference(&operands[1]) as usize;
+                if self.dereference(&operands[0])? == 0 {
+                    self.program_counter = self.dereference(&operands[1])? as usize;
                 }
             },
             Operation::Add => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let c = self.dereference(&operands[2]);
-                    self.registers[a as usize] = (b + c) & 0x7FFF;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                let c = self.dereference(&operands[2])?;
+                //Registers can hold more than 15 bits, when loaded with RMEM.
+                self.registers[a] = b.wrapping_add(c) & 0x7FFF;
             },
             Operation::Mult => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]) as u32;
-                    let c = self.dereference(&operands[2]) as u32;
-                    self.registers[a as usize] = (b * c) as u16 & 0x7FFF;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])? as u32;
+                let c = self.dereference(&operands[2])? as u32;
+                self.registers[a] = (b * c) as u16 & 0x7FFF;
             },
             Operation::Mod => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let c = self.dereference(&operands[2]);
-                    self.registers[a as usize] = b % c;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                let c = self.dereference(&operands[2])?;
+                if c == 0 {
+                    return Err(RuntimeError::ErrDivisionByZero);
                 }
+                self.registers[a] = b % c;
             },
             Operation::And => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let c = self.dereference(&operands[2]);
-                    self.registers[a as usize] = b & c;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                let c = self.dereference(&operands[2])?;
+                self.registers[a] = b & c;
             },
             Operation::Or => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let c = self.dereference(&operands[2]);
-                    self.registers[a as usize] = b | c;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                let c = self.dereference(&operands[2])?;
+                self.registers[a] = b | c;
             },
             Operation::Not => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    self.registers[a as usize] = b ^ 0x7FFF;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                self.registers[a] = b ^ 0x7FFF;
             },
             Operation::Rmem => {
-                if let ParsedValue::Register(a) = operands[0] {
-                    let b = self.dereference(&operands[1]);
-                    let val:u16;
-                    if self.memory.len() as u16 >= b {
-                        val = self.memory[b as usize];
-                    } else {
-                        val = 0;
-                    }
-                    self.registers[a as usize] = val;
-                } else {
-                    return Err(RuntimeError::ErrRegisterExpected);
+                let a = register_index(&operands[0]);
+                let b = self.dereference(&operands[1])?;
+                if b as usize >= ADDRESS_SPACE {
+                    return Err(RuntimeError::ErrAddressOutOfRange(b));
                 }
+                self.registers[a] = self.word_at(b);
             },
             Operation::Wmem => {
-                let a = self.dereference(&operands[0]);
-                let b = self.dereference(&operands[1]);
-                if self.memory.len() < a as usize {
-                    self.memory.resize((a+1) as usize, 0);
-                }
-                self.memory[a as usize] = b;
+                let a = self.dereference(&operands[0])?;
+                let b = self.dereference(&operands[1])?;
+                self.set_word(a, b)?;
             },
             Operation::Call => {
-                if let ParsedValue::Error(a) = operands[0] {
-                    return Err(RuntimeError::ErrUnknownOperand(a));
-                } else {
-                    self.stack.push(self.program_counter);
-                    self.program_counter = self.dereference(&operands[0]).into();
+                if let Err(e) = self.push_stack(self.program_counter) {
+                    self.program_counter = old_count;
+                    return Err(e);
                 }
+                self.program_counter = self.dereference(&operands[0])?.into();
             },
             Operation::Ret => {
-                if self.stack.len() > 0 {
-                    self.program_counter = self.stack.pop().expect("Stack empty!");
+                if let Some(address) = self.stack.pop() {
+                    self.program_counter = address;
                 } else {
                     return Err(RuntimeError::ErrStackEmpty);
                 }
             },
             Operation::Out => {
-                let print_char:char = char::from_u32(self.dereference(&operands[0])as u32).unwrap_or('�');
+                let value = self.dereference(&operands[0])?;
+                if self.strict_output && value > 0x7f {
+                    return Err(RuntimeError::ErrNonAsciiOutput(value));
+                }
+                if self.recent_output.len() >= RECENT_OUTPUT_LEN {
+                    self.recent_output.pop_front();
+                }
+                self.recent_output.push_back((value & 0xff) as u8);
+                let print_char:char = if value <= 0x7f {char::from(value as u8)} else {char::REPLACEMENT_CHARACTER};
                 to_print = Some(print_char);
             },
             Operation::In => {
-                //Assumption: Since <a> is only a single target, treat this as "read until newline, then put the last character
-                //before the newline into <a>."
-                if let ParsedValue::Literal(_) = operands[0] {
-                    return Err(RuntimeError::ErrRegisterExpected);
-                }
-                let register_number = {
-                    if let ParsedValue::Register(x) = operands[0] {
-                        x as usize
-                    } else {
-                        0xff as usize
-                    }
-                };
-                if let Some(ch) = self.input_buffer.pop() {
-                    self.registers[register_number] = ch;
+                let a = register_index(&operands[0]);
+                if let Some(ch) = self.input_buffer.pop_front() {
+                    self.registers[a] = ch;
                 } else {
                     self.program_counter = old_count; //Stall the program if the buffer is empty.
                     return Err(RuntimeError::ErrInputEmpty);
                 }
             },
             Operation::Noop => (),
-            Operation::Error(_) => return Err(RuntimeError::ErrUnknownOperation(self.memory[old_count])),
+            Operation::Error(code) => return Err(RuntimeError::ErrUnknownOperation(code)),
         };
         Ok((current_instruction,operands,to_print))
     }
 
     pub fn register_snapshot(&self) -> RegisterState {
         RegisterState { 
-            registers: self.registers.clone(), 
+            registers: self.registers, 
             stack_depth: self.stack.len(), 
-            program_counter: (self.program_counter & 0xffff) as u16
+            program_counter: self.pc()
         }
     }
 
@@ -316,45 +619,307 @@ impl VirtualMachine {
         use RuntimeState::*;
         let mut run_state = Pause;
         let mut delay:usize = 0;
+        let mut loop_detector = if self.loop_threshold > 0 {
+            Some(LoopDetector::new(self.loop_threshold))
+        } else {
+            None
+        };
+        let mut tracker:Option<InstructionTracker> = None;
+        let mut output_log:Option<BufWriter<File>> = None;
+        let mut pending_steps:Vec<ProgramStep> = Vec::with_capacity(STEP_BATCH);
+        //Characters output so far, so every step can tell the UI how much output came before it.
+        let mut output_len:usize = 0;
+        //Address and previous value of every word changed by hand, most recent last.
+        let mut edit_history:VecDeque<(u16,u16)> = VecDeque::with_capacity(EDIT_HISTORY_LEN);
+        let mut breakpoints:HashSet<u16> = HashSet::new();
+        let mut opcode_breaks:HashSet<u16> = HashSet::new();
+        let mut save_slots:[Option<Snapshot>;SAVE_SLOTS] = Default::default();
         loop {
-            run_state = output.read_state(run_state == Pause)
-                .unwrap_or(run_state);
+            if run_state == Pause {
+                output.set_running(false);
+                output.set_queued_input(self.input_buffer.len());
+                send_steps(output, &mut pending_steps);
+                //Let the UI know what comes next, while waiting for a new command.
+                let _ = output.write_memory_view(self.memory_view(self.pc(), PREVIEW_WORDS));
+            }
+            let new_state = output.read_state(run_state == Pause);
+            if new_state.is_none() && run_state == Pause {
+                //Nothing is left to give a command.
+                break;
+            }
+            if let Some(new_state) = new_state {
+                match new_state {
+                    TraceOperations(path) => {
+                        match InstructionTracker::new(&path, TraceFormat::Compact) {
+                            Ok(new_tracker) => tracker = Some(new_tracker),
+                            Err(e) => output.runtime_err(format!("Could not start trace: {e}")),
+                        }
+                        continue;
+                    },
+                    TraceOperationsCsv(path) => {
+                        match InstructionTracker::new(&path, TraceFormat::Csv) {
+                            Ok(new_tracker) => tracker = Some(new_tracker),
+                            Err(e) => output.runtime_err(format!("Could not start trace: {e}")),
+                        }
+                        continue;
+                    },
+                    TraceStop => {
+                        tracker = None;
+                        continue;
+                    },
+                    AddBreakpoint(address) => {
+                        breakpoints.insert(address);
+                        continue;
+                    },
+                    RemoveBreakpoint(address) => {
+                        breakpoints.remove(&address);
+                        continue;
+                    },
+                    ClearBreakpoints => {
+                        breakpoints.clear();
+                        continue;
+                    },
+                    SetBreakpoints(addresses) => {
+                        breakpoints = addresses.into_iter().collect();
+                        continue;
+                    },
+                    BreakOnOpcode(opcode) => {
+                        opcode_breaks.insert(opcode);
+                        continue;
+                    },
+                    RemoveOpcodeBreak(opcode) => {
+                        opcode_breaks.remove(&opcode);
+                        continue;
+                    },
+                    ClearOpcodeBreaks => {
+                        opcode_breaks.clear();
+                        continue;
+                    },
+                    Inject(words) => {
+                        let reg_state = self.register_snapshot();
+                        match self.inject(&words) {
+                            Ok((inst,operands,to_print)) => {
+                                let repr = format!("(injected) {}",describe_step(&inst, &operands, &reg_state));
+                                pending_steps.push(ProgramStep::step(reg_state, repr, output_len));
+                                if let Some(to_print) = to_print {
+                                    output_len += 1;
+                                    let _ = output.write_output(to_print);
+                                }
+                            },
+                            Err(e) => output.runtime_err(format!("Injected instruction failed: {e}")),
+                        }
+                        continue;
+                    },
+                    TraceMark(note) => {
+                        match tracker.as_mut() {
+                            Some(active_tracker) => if let Err(e) = active_tracker.mark(&note) {
+                                output.runtime_err(format!("Could not write to trace: {e}"));
+                            },
+                            None => output.runtime_err(String::from("Not tracing; the note was not written anywhere.")),
+                        }
+                        continue;
+                    },
+                    SaveBinary(path) => {
+                        if let Err(e) = self.dump_memory_raw(&path) {
+                            output.runtime_err(format!("Could not save memory as a binary: {e}"));
+                        }
+                        continue;
+                    },
+                    DumpMemory(start, end, path) => {
+                        if let Err(e) = self.dump_memory_range_to_file(start, end, &path, true) {
+                            output.runtime_err(format!("Could not dump memory: {e}"));
+                        }
+                        continue;
+                    },
+                    AnalyzeNow(path) => {
+                        if let Err(e) = self.analyze_memory(&path) {
+                            output.runtime_err(format!("Could not analyze memory: {e:?}"));
+                        }
+                        continue;
+                    },
+                    QuickSave(slot) => {
+                        match save_slots.get_mut(slot as usize) {
+                            Some(save_slot) => *save_slot = Some(self.snapshot()),
+                            None => output.runtime_err(format!("There is no save slot {slot}.")),
+                        }
+                        continue;
+                    },
+                    QuickLoad(slot) => {
+                        match save_slots.get(slot as usize) {
+                            Some(Some(snapshot)) => {
+                                self.restore(snapshot);
+                                //Edits made since may not apply to the memory as it was.
+                                edit_history.clear();
+                                output.set_finished(false);
+                            },
+                            _ => output.runtime_err(format!("Nothing saved in slot {slot}.")),
+                        }
+                        continue;
+                    },
+                    Reset => {
+                        if self.reset() {
+                            edit_history.clear();
+                            output.set_finished(false);
+                            //Show the registers and memory as they are now, not as they were.
+                            pending_steps.push(ProgramStep::step(self.register_snapshot(), "(reset)".into(), output_len));
+                            send_steps(output, &mut pending_steps);
+                            let _ = output.write_memory_view(self.memory_view(self.pc(), PREVIEW_WORDS));
+                        } else {
+                            output.runtime_err(String::from("Cannot reset, the initial memory was not kept."));
+                        }
+                        continue;
+                    },
+                    LogOutput(path) => {
+                        match OpenOptions::new().create(true).append(true).open(&path) {
+                            Ok(log_file) => output_log = Some(BufWriter::new(log_file)),
+                            Err(e) => output.runtime_err(format!("Could not open output log: {e}")),
+                        }
+                        continue;
+                    },
+                    StopLogOutput => {
+                        if let Some(mut log) = output_log.take() {
+                            if let Err(e) = log.flush() {
+                                output.runtime_err(format!("Could not write output log: {e}"));
+                            }
+                        }
+                        continue;
+                    },
+                    SetMemory(address,value) => {
+                        let previous = self.word_at(address);
+                        if let Err(e) = self.set_word(address, value) {
+                            output.runtime_err(format!("{e}"));
+                            continue;
+                        }
+                        if edit_history.len() >= EDIT_HISTORY_LEN {
+                            edit_history.pop_front();
+                        }
+                        edit_history.push_back((address, previous));
+                        continue;
+                    },
+                    //Stop right away, without executing another instruction first.
+                    Terminate => break,
+                    UndoEdit => {
+                        match edit_history.pop_back() {
+                            //Only changes that went through are recorded, so this can't fail.
+                            Some((address,previous)) => {let _ = self.set_word(address, previous);},
+                            None => output.runtime_err(String::from("No changes to memory left to undo.")),
+                        }
+                        continue;
+                    },
+                    ProfileStart => {
+                        self.profile = Some(HashMap::new());
+                        continue;
+                    },
+                    ProfileStop(path) => {
+                        if let Some(counts) = self.profile.take() {
+                            if let Err(e) = self.write_profile(&counts, &path) {
+                                output.runtime_err(format!("Could not save profile: {e}"));
+                            }
+                        }
+                        continue;
+                    },
+                    //Running for no steps at all changes nothing.
+                    RunForSteps(0) => continue,
+                    StepOut => {
+                        //Nothing was called, so there is nothing to return from.
+                        if self.stack.is_empty() {
+                            output.runtime_err(String::from("The stack is empty, there is no call to step out of."));
+                            continue;
+                        }
+                        run_state = RunUntilReturn(self.stack.len());
+                        output.set_running(true);
+                    },
+                    other => {
+                        run_state = match other {
+                            //Run until the instruction after the CALL, like a breakpoint that is only used once.
+                            StepOver if Operation::from(self.word_at(self.pc())) == Operation::Call => {
+                                let after_call = self.pc().wrapping_add(1 + Operation::Call.operands());
+                                RunUntilAddress(after_call)
+                            },
+                            StepOver => SingleStep,
+                            other => other,
+                        };
+                        output.set_running(run_state != Pause);
+                    },
+                }
+            }
 
             let reg_state = self.register_snapshot();
+            let mut printed = false;
+            let mut returned = false;
+            let mut break_on_opcode = false;
 
             match self.operation() {
                 Ok((inst,operands,to_print)) => {
-                    // Set up the "representation" of the executed instruction; a string giving
-                    // a human-readable version.
-                    let mut repr = format!("{inst}");
-                    for pv in operands {
-                        repr.push_str(&format!(" {pv}")[..]);
+                    printed = to_print.is_some();
+                    returned = inst == Operation::Ret;
+                    break_on_opcode = opcode_breaks.iter().any(|opcode| Operation::from(*opcode) == inst);
+                    if inst == Operation::In {
+                        output.set_queued_input(self.input_buffer.len());
+                    }
+                    if let Some(counts) = &mut self.profile {
+                        *counts.entry(reg_state.program_counter).or_insert(0) += 1;
+                    }
+                    if let Some(active_tracker) = &mut tracker {
+                        let next_pc = self.pc();
+                        if let Err(e) = active_tracker.instruction(reg_state.program_counter, &inst, &operands, &reg_state, next_pc) {
+                            output.runtime_err(format!("Could not write trace, tracing stopped: {e}"));
+                            tracker = None;
+                        }
+                    }
+                    if let Some(detector) = &mut loop_detector {
+                        if to_print.is_some() {
+                            detector.reset();
+                        } else if detector.is_stuck(&reg_state) {
+                            output.runtime_err(format!("Possible infinite loop at PC {:04x}",reg_state.program_counter));
+                            run_state = Pause;
+                        }
+                    }
+                    let repr = describe_step(&inst, &operands, &reg_state);
+                    pending_steps.push(ProgramStep::step(reg_state, repr, output_len));
+                    if pending_steps.len() >= STEP_BATCH || delay > 0 {
+                        send_steps(output, &mut pending_steps);
                     }
-                    let _ = output.write_step(
-                        ProgramStep::step(
-                            reg_state, 
-                            repr));
                     if let Some(to_print) = to_print {
+                        output_len += 1;
                         let _ = output.write_output(to_print);
+                        if let Some(log) = &mut output_log {
+                            if let Err(e) = write!(log,"{to_print}") {
+                                output.runtime_err(format!("Could not write output log, logging stopped: {e}"));
+                                output_log = None;
+                            }
+                        }
                     }
                 },
                 Err(RuntimeError::ErrInputEmpty) => {
+                    send_steps(output, &mut pending_steps);
                     let new_input = output.read_input(); //Note that this is a blocking operation.
-                    self.input_buffer.extend(
-                        new_input.chars() //take the characters of the string,
-                        .filter(|ch| ch.is_ascii())// Keep the ones that are ASCII characters,
-                        .map(|ch| (ch as u64 &0x7f) as u16)// Turn the characters into 16-bit values (since that's what the VM works with,)
-                        .rev()//and finally reverse the string, so that the first character is at the top of the 'stack'.
-                    );
+                    self.push_input(&new_input);
+                    if new_input.is_empty() && output.input_ended() {
+                        if self.zero_at_end_of_input {
+                            //Not through push_input; a line-feed after it would be read next.
+                            self.input_buffer.push_back(0);
+                        } else {
+                            output.runtime_err(format!("{}",RuntimeError::ErrInputEOF));
+                            output.set_finished(true);
+                            run_state = RuntimeState::Terminate;
+                        }
+                    }
+                    output.set_queued_input(self.input_buffer.len());
                 },
                 Err(RuntimeError::ErrFinished) => {
-                    let _ = output.write_step(ProgramStep::step(
-                        reg_state,
-                        "HALT".into()
-                    ));
-                    run_state = RuntimeState::Terminate;
+                    pending_steps.push(ProgramStep::step(reg_state, "HALT".into(), output_len));
+                    output.set_finished(true);
+                    //Stay around, so the program can still be looked at or reset.
+                    run_state = Pause;
                 }
                 Err(e) => {
+                    //The CALL or PUSH didn't happen, or the instruction couldn't even be read;
+                    // carrying on would just try it again.
+                    if self.pause_on_error || matches!(e, RuntimeError::ErrStackOverflow(_) | RuntimeError::ErrTruncatedInstruction(_) | RuntimeError::ErrUnknownOperand(_)) {
+                        pending_steps.push(ProgramStep::step(reg_state, format!("ERROR {e}"), output_len));
+                        run_state = Pause;
+                    }
                     output.runtime_err(format!("{e}"));
                 },
             }
@@ -370,9 +935,19 @@ impl VirtualMachine {
                 RunForSteps(steps) => run_state = RunForSteps(steps-1), 
                 // Check if the address is part of the instruction about to be executed; pause after if it is.
                 RunUntilAddress(addr) => {
-                    let inst_start = (self.program_counter &0xffff) as u16;
-                    let inst_end = inst_start + (Operation::from(self.memory[self.program_counter]).operands());
-                    if inst_start == addr ||  (inst_start < addr && addr <= inst_end) {
+                    if self.next_instruction_covers(addr) {
+                        run_state = Pause;
+                    }
+                },
+                // Only a RET can take the stack back out of the routine.
+                RunUntilReturn(depth) => {
+                    if returned && self.stack.len() < depth {
+                        run_state = Pause;
+                    }
+                },
+                // Only new output can complete the text, so only look right after some.
+                RunUntilOutputContains(ref text) => {
+                    if printed && self.output_ends_with(text) {
                         run_state = Pause;
                     }
                 },
@@ -386,25 +961,110 @@ impl VirtualMachine {
                 },
                 // quit immediately.
                 Terminate => break,
+                // Handled as soon as they arrive, never stored as the run state.
+                StepOver | StepOut | ProfileStart | ProfileStop(_) | TraceOperations(_) | TraceOperationsCsv(_) | TraceStop | TraceMark(_) |
+                LogOutput(_) | StopLogOutput | Reset | QuickSave(_) | QuickLoad(_) | AnalyzeNow(_) | DumpMemory(..) | SaveBinary(_) | SetMemory(..) | UndoEdit | Inject(_) |
+                AddBreakpoint(_) | RemoveBreakpoint(_) | ClearBreakpoints | SetBreakpoints(_) |
+                BreakOnOpcode(_) | RemoveOpcodeBreak(_) | ClearOpcodeBreaks => (),
+            }
+            if break_on_opcode || breakpoints.iter().any(|addr| self.next_instruction_covers(*addr)) {
+                run_state = Pause;
             }
 
             if delay > 0 {
                 std::thread::sleep(std::time::Duration::from_millis(delay.try_into().expect("Invalid delay duration.")));
             }
         }
+        send_steps(output, &mut pending_steps);
+        if let Some(mut log) = output_log.take() {
+            let _ = log.flush();
+        }
+    }
+
+    /// Whether the most recent output of the program is `text`.
+    fn output_ends_with(&self, text:&str) -> bool {
+        let text = text.as_bytes();
+        text.len() <= self.recent_output.len()
+            && self.recent_output.iter().skip(self.recent_output.len() - text.len()).eq(text.iter())
+    }
+
+    /// Whether `addr` is part of the instruction about to be executed, operands included.
+    fn next_instruction_covers(&self, addr:u16) -> bool {
+        let inst_start = self.pc();
+        let inst_end = inst_start.saturating_add(Operation::from(self.word_at(inst_start)).operands());
+        inst_start == addr || (inst_start < addr && addr <= inst_end)
+    }
+
+    /// Run the static analysis on memory as it is right now, including the current
+    /// program counter as an entry point, so code that was unpacked at runtime shows up.
+    fn analyze_memory(&self, save_location:&str) -> Result<(),AnalysisError> {
+        let pc = self.pc();
+        parse_program_and_save(
+            &self.memory,
+            &format!("memory snapshot at PC {pc:04x}"),
+            std::ffi::OsStr::new(save_location),
+            OutputFormat::Listing,
+            Some(vec![pc]),
+            HashMap::new())
     }
 
-    pub fn dump_memory_to_file(&self, save_location:&str) -> io_result<()>{
+    /// Write the most-executed addresses of a profile to a file, hottest first.
+    fn write_profile(&self, counts:&HashMap<u16,u64>, save_location:&str) -> io_result<()> {
+        let destination_file = File::create(save_location)?;
+        let mut out_writer = BufWriter::new(destination_file);
+        let total:u64 = counts.values().sum();
+        writeln!(&mut out_writer,"{total} instructions executed at {} addresses.",counts.len())?;
+        let hottest = counts.iter()
+            .sorted_by(|(addr_a,count_a),(addr_b,count_b)| count_b.cmp(count_a).then(addr_a.cmp(addr_b)))
+            .take(PROFILE_TOP_N);
+        for (address,count) in hottest {
+            let operation = Operation::from(self.word_at(*address));
+            writeln!(&mut out_writer,"{address:04X}: {count:>12} {operation}")?;
+        }
+        out_writer.flush()
+    }
+
+    /// Dump all of memory to a file. With `classify`, words the static analysis (started
+    /// at address 0 and at the program counter) doesn't find to be executed are only shown
+    /// as raw values, so data doesn't show up as made-up instructions.
+    pub fn dump_memory_to_file(&self, save_location:&str, classify:bool) -> io_result<()>{
+        self.dump_memory_range_to_file(0, u16::MAX, save_location, classify)
+    }
+
+    /// Write all of memory as a program binary: every word as two bytes, little-endian, the
+    /// way init_from_file reads them. Loading the file again gives this same memory. The
+    /// checksum of memory goes in a file next to it, for init_from_file to check.
+    pub fn dump_memory_raw(&self, save_location:&str) -> io_result<()> {
+        let bytes:Vec<u8> = self.memory.iter().flat_map(|word| word.to_le_bytes()).collect();
+        std::fs::write(save_location, bytes)?;
+        std::fs::write(checksum_path(save_location), format!("{:08x}\n",self.memory_checksum()))
+    }
+
+    /// Dump the words from `start` up to and including `end`, in the same format as
+    /// `dump_memory_to_file`. The range is cut off at the end of memory.
+    pub fn dump_memory_range_to_file(&self, start:u16, end:u16, save_location:&str, classify:bool) -> io_result<()>{
+        if start > end {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("range start {start:04X} is past its end {end:04X}")));
+        }
+        let stop = (end as usize + 1).min(self.memory.len());
+        let start = (start as usize).min(stop);
         //Set up the output writer.
         let destination_file = File::create(save_location)?;
         let mut out_writer = BufWriter::new(destination_file);
+        writeln!(&mut out_writer,"Memory checksum: {:08x}",self.memory_checksum())?;
+        writeln!(&mut out_writer,"Words {start:04X} up to {stop:04X}")?;
         //Will need to have some control over the iterator, both for operands and for raw data.
-        let mut memory_iterator = self.memory.iter().enumerate();
+        let mut memory_iterator = self.memory[start..stop].iter().enumerate().map(|(offset,word)| (start + offset,word));
+        let analysis = classify.then(|| analyze(&self.memory, &[self.pc()]));
 
 
         while let Some((index,current_word)) = memory_iterator.next() {
             let value = Operation::from(*current_word);
-            if let Operation::Error(raw) = value {
+            let wordcount = 1 + value.operands() as usize;
+            //Unknown operations claim far more operands than memory holds, so they end up here too.
+            let is_data = analysis.as_ref().is_some_and(|analysis| !analysis.is_code(index as u16));
+            if is_data || index + wordcount > stop {
+                let raw = *current_word;
                 //Must be some raw value. Print both the hex value, and (if possible) the ASCII characters.
                 let low = (raw & 0xff) as u8;
                 let hi = ((raw>>8) &0xff) as u8;
@@ -428,8 +1088,7 @@ impl VirtualMachine {
                     }
                 )?;
             } else {
-                let wordcount = 1 + value.operands() as usize;
-                let raw_bytes = &self.memory[index..=(wordcount+index)];
+                let raw_bytes = &self.memory[index..(index+wordcount)];
                 let mut ascii_chars:String = String::with_capacity(8);
 
                 for raw_word in raw_bytes {
@@ -465,7 +1124,7 @@ impl VirtualMachine {
                         ParsedValue::Error(e) => write!(&mut out_writer,"!{e:04X} ")?,
                     }
                 }
-                writeln!(&mut out_writer,"")?;
+                writeln!(&mut out_writer)?;
             }
         }
 
@@ -473,9 +1132,48 @@ impl VirtualMachine {
     }
 }
 
+/// Set up the "representation" of an executed instruction; a string giving a
+/// human-readable version. Registers also show the value they held.
+fn describe_step(inst:&Operation, operands:&[ParsedValue], reg_state:&RegisterState) -> String {
+    let mut repr = format!("{inst}");
+    for pv in operands {
+        if let ParsedValue::Register(r) = pv {
+            repr.push_str(&format!(" {pv:#}(={:04x})",reg_state.registers[*r as usize])[..]);
+        } else {
+            repr.push_str(&format!(" {pv}")[..]);
+        }
+    }
+    repr
+}
+
+/// Make sure every operand that has to be a register is one.
+fn check_operands(operation:&Operation, operands:&[ParsedValue]) -> Result<(),RuntimeError> {
+    for (kind,operand) in operation.operand_spec().iter().zip(operands) {
+        if *kind == OperandKind::Register && !matches!(operand, ParsedValue::Register(_)) {
+            return Err(RuntimeError::ErrRegisterExpected);
+        }
+    }
+    Ok(())
+}
+
+/// Index of the register an operand names. Only for operands that passed `check_operands`.
+fn register_index(operand:&ParsedValue) -> usize {
+    match operand {
+        ParsedValue::Register(r) => *r as usize,
+        _ => unreachable!("operand was checked to be a register"),
+    }
+}
+
+/// Hand all collected steps to the UI at once.
+fn send_steps(output:&mut impl VmInterface, pending_steps:&mut Vec<ProgramStep>) {
+    if !pending_steps.is_empty() {
+        let _ = output.write_steps(std::mem::replace(pending_steps, Vec::with_capacity(STEP_BATCH)));
+    }
+}
+
 impl Display for VirtualMachine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmtResult {
-        write!(f,"[PC:{}; R:{:?}; mem {} stack {:?}]",self.program_counter,self.registers,self.memory.len(),self.stack)
+        write!(f,"[PC:{}; R:{:?}; mem {} checksum {:08x} stack {:?}]",self.program_counter,self.registers,self.memory.len(),self.memory_checksum(),self.stack)
     }
 }
 
@@ -493,3 +1191,274 @@ impl<'a> Iterator for VirtualMachineStep<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_interface::{TestInterface,Divergence,compare_runs};
+
+    /// IN R0, OUT R0, JMP 0: prints its input back.
+    const ECHO:[u16;6] = [20,0x8000, 19,0x8000, 6,0];
+
+    /// A path in the temporary directory, different for every test that asks for one.
+    fn temp_path(name:&str) -> String {
+        std::env::temp_dir().join(format!("synapone-{}-{name}",std::process::id())).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn prints_a() {
+        //OUT 'A', HALT
+        let mut vm = VirtualMachine::init_from_sequence(&[19,65,0]);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "A");
+        assert!(interface.finished);
+        assert_eq!(interface.steps.len(), 2);
+        assert_eq!(interface.steps[1].instruction, "HALT");
+    }
+
+    #[test]
+    fn reset_after_halt() {
+        //SET R0 7, OUT 'A', HALT
+        let mut vm = VirtualMachine::init_from_sequence(&[1,0x8000,7, 19,65, 0]);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run, RuntimeState::Reset]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "A");
+        assert!(!interface.finished);
+        let last = interface.steps.last().unwrap();
+        assert_eq!(last.instruction, "(reset)");
+        assert_eq!(last.registers.program_counter, 0);
+        assert_eq!(last.registers.registers[0], 0);
+        assert_eq!(interface.memory_view.unwrap().start, 0);
+
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "A");
+        assert!(interface.finished);
+    }
+
+    #[test]
+    fn run_for_no_steps() {
+        let mut vm = VirtualMachine::init_from_sequence(&[19,65,0]);
+        let mut interface = TestInterface::new(vec![RuntimeState::RunForSteps(0)]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "");
+        assert_eq!(vm.program_counter, 0);
+    }
+
+    #[test]
+    fn echoes_input() {
+        let mut vm = VirtualMachine::init_from_sequence(&ECHO);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]).with_input(&["hi\n"]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "hi\n");
+    }
+
+    #[test]
+    fn same_program_runs_the_same() {
+        let vm = VirtualMachine::init_from_sequence(&ECHO);
+        assert_eq!(compare_runs(&vm, &vm.clone(), &["look\n","go north\n"], 100), Ok(()));
+    }
+
+    #[test]
+    fn patch_shows_where_output_diverges() {
+        //OUT 'A', OUT 'B', HALT; the patch prints a C instead of the B.
+        let original = VirtualMachine::init_from_sequence(&[19,65,19,66,0]);
+        let mut patched = original.clone();
+        patched.set_word(3, 67).unwrap();
+        assert_eq!(compare_runs(&original, &patched, &[], 10), Err(Divergence { position: 1, original: Some('B'), patched: Some('C') }));
+        //Not far enough along to reach the patched instruction.
+        assert_eq!(compare_runs(&original, &patched, &[], 1), Ok(()));
+    }
+
+    #[test]
+    fn instruction_cut_off_by_end_of_memory() {
+        //ADD R0 R1, missing its last operand.
+        let mut vm = VirtualMachine::init_from_sequence(&[9,0x8000,0x8001]);
+        assert!(matches!(vm.operation(), Err(RuntimeError::ErrTruncatedInstruction(0))));
+        let mut interface = TestInterface::new(vec![RuntimeState::SingleStep]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.errors, ["Instruction at 0000 runs past the end of memory."]);
+
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.errors, ["Instruction at 0000 runs past the end of memory."]);
+        assert_eq!(interface.steps.last().unwrap().instruction, "ERROR Instruction at 0000 runs past the end of memory.");
+    }
+
+    #[test]
+    fn mod_by_zero() {
+        //MOD R0 5 0
+        let mut vm = VirtualMachine::init_from_sequence(&[11,0x8000,5,0,0]);
+        assert!(matches!(vm.operation(), Err(RuntimeError::ErrDivisionByZero)));
+    }
+
+    #[test]
+    fn arithmetic_on_wide_registers() {
+        //RMEM R0 8, ADD R1 R0 R0, MULT R2 R0 R0, then the word 0xffff.
+        let mut vm = VirtualMachine::init_from_sequence(&[15,0x8000,8, 9,0x8001,0x8000,0x8000, 0, 0xffff]);
+        for _ in 0..2 {
+            vm.operation().unwrap();
+        }
+        assert_eq!(vm.registers[1], 0x7ffe);
+        //0xffff * 0xffff = 0xfffe0001
+        vm.inject(&[10,0x8002,0x8000,0x8000]).unwrap();
+        assert_eq!(vm.registers[2], 0x0001);
+    }
+
+    #[test]
+    fn recursion_stops_at_stack_limit() {
+        //CALL 0, calling itself forever.
+        let mut vm = VirtualMachine::init_from_sequence(&[17,0]);
+        vm.set_max_stack_depth(Some(10));
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.errors, ["Stack is already 10 values deep, the most it may hold."]);
+        assert_eq!(vm.stack.len(), 10);
+        assert_eq!(vm.program_counter, 0);
+        assert_eq!(interface.steps.last().map(|step| step.instruction.as_str()), Some("ERROR Stack is already 10 values deep, the most it may hold."));
+    }
+
+    #[test]
+    fn output_past_ascii() {
+        //OUT 0xe9, HALT
+        let program = [19,0xe9,0];
+        let mut vm = VirtualMachine::init_from_sequence(&program);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "\u{fffd}");
+        assert!(interface.errors.is_empty());
+
+        let mut vm = VirtualMachine::init_from_sequence(&program);
+        vm.set_strict_output(true);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "");
+        assert_eq!(interface.errors, ["OUT instruction with value 00e9, which is not 7-bit ASCII."]);
+    }
+
+    #[test]
+    fn saved_binary_loads_the_same() {
+        let mut vm = VirtualMachine::init_from_sequence(&ECHO);
+        vm.set_word(0x10, 0x7fff).unwrap();
+        let path = temp_path("roundtrip.bin");
+        vm.dump_memory_raw(&path).unwrap();
+        let loaded = VirtualMachine::init_from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(checksum_path(&path));
+        assert_eq!(loaded.unwrap().memory(), vm.memory());
+    }
+
+    #[test]
+    fn corrupted_binary_is_refused() {
+        let vm = VirtualMachine::init_from_sequence(&ECHO);
+        let path = temp_path("corrupted.bin");
+        vm.dump_memory_raw(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[2] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        let loaded = VirtualMachine::init_from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(checksum_path(&path));
+        let error = loaded.err().expect("a corrupted binary should not load");
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("instead of the"), "{error}");
+    }
+
+    #[test]
+    fn input_after_the_end() {
+        //OUT 'A', IN R0, OUT R0, HALT
+        let program = [19,65, 20,0x8000, 19,0x8000, 0];
+        let mut vm = VirtualMachine::init_from_sequence(&program);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "A");
+        assert_eq!(interface.errors, ["IN instruction executed after all input was used up."]);
+        assert!(interface.finished);
+        assert_eq!(vm.program_counter, 2);
+
+        let mut vm = VirtualMachine::init_from_sequence(&program);
+        vm.set_zero_at_end_of_input(true);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "A\0");
+        assert!(interface.errors.is_empty());
+        assert_eq!(interface.steps.last().map(|step| step.instruction.as_str()), Some("HALT"));
+    }
+
+    #[test]
+    fn line_input_keeps_pieces_apart() {
+        //A script line, then a typed command sent without a line-feed, then another command.
+        let run = |line_input:bool| {
+            let mut vm = VirtualMachine::init_from_sequence(&ECHO);
+            vm.set_line_input(line_input);
+            vm.prefill_input("go north");
+            let mut interface = TestInterface::new(vec![RuntimeState::Run]).with_input(&["look", "inv\n"]);
+            vm.run_program(&mut interface);
+            interface.output
+        };
+        assert_eq!(run(true), "go north\nlook\ninv\n");
+        assert_eq!(run(false), "go north\nlookinv\n");
+    }
+
+    #[test]
+    fn break_on_first_out() {
+        //NOOP, OUT 'A', OUT 'B', HALT
+        let mut vm = VirtualMachine::init_from_sequence(&[21, 19,65, 19,66, 0]);
+        let mut interface = TestInterface::new(vec![RuntimeState::BreakOnOpcode(19), RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "A");
+        assert_eq!(vm.program_counter, 3);
+        assert_eq!(interface.steps.len(), 2);
+    }
+
+    #[test]
+    fn jump_to_self_is_a_loop() {
+        //JMP 0
+        let mut vm = VirtualMachine::init_from_sequence(&[6,0]);
+        let mut interface = TestInterface::new(vec![RuntimeState::RunForSteps(1000)]);
+        vm.run_program(&mut interface);
+        assert!(interface.errors.is_empty());
+
+        vm.set_loop_threshold(100);
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.errors, ["Possible infinite loop at PC 0000"]);
+        assert_eq!(interface.steps.len(), 101);
+    }
+
+    #[test]
+    fn script_is_read_in_order() {
+        let path = temp_path("script.txt");
+        std::fs::write(&path, "take tablet\r\n\ngo doorway\nlook").unwrap();
+        let mut vm = VirtualMachine::init_from_sequence(&ECHO);
+        let loaded = vm.prefill_input_from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        loaded.unwrap();
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.output, "take tablet\n\ngo doorway\nlook\n");
+    }
+
+    #[test]
+    fn result_must_go_to_a_register() {
+        //GT 5 R0 R1
+        let mut vm = VirtualMachine::init_from_sequence(&[5,5,0x8000,0x8001]);
+        assert!(matches!(vm.operation(), Err(RuntimeError::ErrRegisterExpected)));
+    }
+
+    #[test]
+    fn operand_past_the_registers() {
+        let vm = VirtualMachine::init_from_sequence(&[]);
+        assert!(matches!(vm.dereference(&ParsedValue::from(0x8010)), Err(RuntimeError::ErrUnknownOperand(0x8010))));
+        //OUT with the operand 0x8010
+        let mut vm = VirtualMachine::init_from_sequence(&[19,0x8010,0]);
+        assert!(matches!(vm.operation(), Err(RuntimeError::ErrUnknownOperand(0x8010))));
+        assert!(matches!(vm.inject(&[19,0x8010]), Err(RuntimeError::ErrUnknownOperand(0x8010))));
+
+        let mut interface = TestInterface::new(vec![RuntimeState::Run]);
+        vm.run_program(&mut interface);
+        assert_eq!(interface.errors, ["Unknown operand with value 8010."]);
+        assert_eq!(interface.output, "");
+    }
+}
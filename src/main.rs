@@ -5,11 +5,28 @@ mod interface;
 mod thread_interface;
 mod instruction;
 mod static_analysis;
+mod code_capture;
+mod instruction_tracker;
+mod theme;
+mod headless;
+mod config_lines;
+mod maze_map;
+mod watch;
+mod ansi;
+#[cfg(test)]
+mod test_interface;
 
 use clap::Parser;
 use std::io::stdin;
+use std::ffi::OsStr;
+use std::collections::HashMap;
 
-use crate::machine::VirtualMachine;
+use crate::machine::{VirtualMachine,ADDRESS_SPACE};
+use crate::code_capture::CodeCapture;
+use crate::theme::Theme;
+use crate::headless::HeadlessInterface;
+use crate::interface::{RuntimeState,trace_to};
+use crate::static_analysis::{parse_program_and_save,diff_programs_and_save,load_annotations,OutputFormat};
 
 #[derive(Parser,Debug)]
 #[command(version, about)]
@@ -17,26 +34,219 @@ struct Args{
     file_name:Option<String>,
 
     #[arg(short)]
-    sequence:Option<String>
+    sequence:Option<String>,
+
+    /// File with lines of input to feed to the program before handing control to the user.
+    #[arg(long)]
+    script:Option<String>,
+
+    /// Input to feed to the program, after --script if both are given. `\n` starts a new
+    /// line and `\\` stands for a backslash.
+    #[arg(long)]
+    input:Option<String>,
+
+    /// File to append any challenge codes found in the program's output to.
+    #[arg(long)]
+    capture_codes:Option<String>,
+
+    /// Pause when the exact same machine state recurs this many times without output,
+    /// such as 10000. Not checked if not given.
+    #[arg(long)]
+    loop_threshold:Option<usize>,
+
+    /// Most values the stack may hold; a CALL or PUSH past this is an error that pauses
+    /// the program. Unlimited if not given.
+    #[arg(long)]
+    max_stack:Option<usize>,
+
+    /// Pause on a runtime error instead of carrying on past it.
+    #[arg(long)]
+    pause_on_error:bool,
+
+    /// Report output of values that aren't 7-bit ASCII as errors, instead of printing a
+    /// replacement character.
+    #[arg(long)]
+    strict_output:bool,
+
+    /// End every piece of input that doesn't end in a line-feed with one, so a line the
+    /// program has started reading is never continued by other input.
+    #[arg(long)]
+    line_input:bool,
+
+    /// When a --run-steps run has used up all its input, have IN read 0 instead of stopping
+    /// the program with an error.
+    #[arg(long, requires = "run_steps")]
+    eof_zero:bool,
+
+    /// File of key=color-index lines, to change the colors of the UI.
+    #[arg(long)]
+    theme:Option<String>,
+
+    /// Number of executed instructions to keep in the history.
+    #[arg(long, default_value_t = 1024)]
+    history_len:usize,
+
+    /// Start running the program right away, instead of waiting for a command.
+    #[arg(long)]
+    start_running:bool,
+
+    /// Show colors and text styles the program asks for with ANSI escape sequences, instead
+    /// of the sequences themselves.
+    #[arg(long)]
+    ansi_colors:bool,
+
+    /// Give each line of input to exactly one request for input from the program, in the
+    /// order they were entered, instead of combining everything entered so far.
+    #[arg(long)]
+    strict_input:bool,
+
+    /// Don't keep a copy of the initial memory around; saves memory, but the VM can't be reset.
+    #[arg(long)]
+    no_reset:bool,
+
+    /// Write a static analysis of the program to this file, instead of running it.
+    #[arg(long)]
+    analyze:Option<String>,
+
+    /// Write the analysis as JSON instead of a listing.
+    #[arg(long, requires = "analyze")]
+    json:bool,
+
+    /// Instead of a listing, write what changed in the code going from the program to
+    /// this other binary, such as a patched version of it.
+    #[arg(long, requires = "analyze", conflicts_with = "json")]
+    diff_with:Option<String>,
+
+    /// Side file of `address ; comment` lines to add to the analysis listing.
+    #[arg(long, requires = "analyze")]
+    annotations:Option<String>,
+
+    /// Extra address to start the analysis from, as a hexadecimal word address, for code
+    /// only reached through computed jumps. May be given more than once.
+    #[arg(long, requires = "analyze", value_parser = parse_hex_address)]
+    entry:Vec<u16>,
+
+    /// Run this many instructions without the interactive UI, then stop.
+    #[arg(long)]
+    run_steps:Option<usize>,
+
+    /// Trace the instructions run with --run-steps to this file; every instruction, as CSV,
+    /// if the file name ends in .csv.
+    #[arg(long, requires = "run_steps")]
+    trace_out:Option<String>,
+
+    /// Dump memory to this file after --run-steps.
+    #[arg(long, requires = "run_steps")]
+    mem_out:Option<String>,
+
+    /// In the --mem-out dump, show words the static analysis finds to be data as raw
+    /// values instead of decoding them as instructions.
+    #[arg(long, requires = "mem_out")]
+    classify_dump:bool,
+
+    /// Without the interactive UI, run until the program asks for input (after --script,
+    /// if given), then walk every exit of every room reachable from there and write a
+    /// Graphviz map of the rooms to this file.
+    #[arg(long)]
+    map_rooms:Option<String>,
+
+    /// Stop mapping after finding this many rooms.
+    #[arg(long, requires = "map_rooms", default_value_t = 200)]
+    map_limit:usize,
 }
 
 fn main() {
     let args = Args::parse();
-    print!("{args:?}");
-    let vm = if let Some(path) = args.file_name {
-        VirtualMachine::init_from_file(&path).expect("Error loading binary file.")
+    let (mut vm, program_name) = if let Some(path) = args.file_name {
+        (VirtualMachine::init_from_file(&path).expect("Error loading binary file."), path)
     } else if let Some(seq) = args.sequence {
-        if seq.len() % 4 != 0 {
-            panic!("Sequence should be a multiple of 16 bits!");
-        }; 
-        let parsed = sequence_decypher(&seq);
-        VirtualMachine::init_from_sequence(&parsed[..])
+        let parsed = match sequence_decypher(&seq) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                eprintln!("Malformed sequence: {message}");
+                std::process::exit(1);
+            },
+        };
+        (VirtualMachine::init_from_sequence(&parsed[..]), String::from("<sequence>"))
     } else {
         let binary_path = get_file_path();
-        VirtualMachine::init_from_file(binary_path.trim()).expect("Error loading binary file.")
+        let binary_path = binary_path.trim();
+        (VirtualMachine::init_from_file(binary_path).expect("Error loading binary file."), String::from(binary_path))
     };
+
+    if let Some(analysis_path) = args.analyze {
+        if let Some(patched_path) = args.diff_with {
+            let patched = VirtualMachine::init_from_file(&patched_path).expect("Error loading binary file to compare with.");
+            diff_programs_and_save(vm.memory(), &program_name, patched.memory(), &patched_path, OsStr::new(&analysis_path), &args.entry)
+                .expect("Error comparing programs.");
+            return;
+        }
+        let format = if args.json {OutputFormat::Json} else {OutputFormat::Listing};
+        let comments = match args.annotations {
+            Some(path) => load_annotations(&path).expect("Error loading annotations file."),
+            None => HashMap::new(),
+        };
+        parse_program_and_save(vm.memory(), &program_name, OsStr::new(&analysis_path), format, Some(args.entry), comments)
+            .expect("Error analyzing program.");
+        return;
+    }
+    if let Some(threshold) = args.loop_threshold {
+        vm.set_loop_threshold(threshold);
+    }
+    vm.set_max_stack_depth(args.max_stack);
+    vm.set_pause_on_error(args.pause_on_error);
+    vm.set_strict_output(args.strict_output);
+    vm.set_line_input(args.line_input);
+    vm.set_zero_at_end_of_input(args.eof_zero);
+    if args.no_reset {
+        vm.discard_initial_memory();
+    }
+    if let Some(script_path) = args.script {
+        vm.prefill_input_from_file(&script_path).expect("Error loading input script.");
+    }
+    if let Some(input) = args.input {
+        vm.prefill_input(&unescape_input(&input));
+    }
     
-    startup::main_interface(vm).expect("Something went wrong running the program!");
+    if let Some(map_path) = args.map_rooms {
+        let map = maze_map::explore(&vm, args.map_limit);
+        println!("Found {} rooms and {} exits.",map.rooms.len(),map.passages.len());
+        maze_map::save_map_as_dot(&map, &map_path).expect("Error writing room map.");
+        return;
+    }
+
+    if let Some(steps) = args.run_steps {
+        let mut commands = Vec::new();
+        if let Some(trace_path) = &args.trace_out {
+            commands.push(trace_to(trace_path.clone()));
+        }
+        if steps > 0 {
+            commands.push(RuntimeState::RunForSteps(steps));
+        }
+        commands.push(RuntimeState::TraceStop);
+        vm.run_program(&mut HeadlessInterface::new(commands));
+        if let Some(memory_path) = args.mem_out {
+            vm.dump_memory_to_file(&memory_path, args.classify_dump).expect("Error writing memory dump.");
+        }
+        return;
+    }
+
+    let code_capture = args.capture_codes.map(|path| {
+        CodeCapture::new(&path).expect("Error opening code capture file.")
+    });
+
+    let theme = match args.theme {
+        Some(path) => {
+            let (theme, warnings) = Theme::load(&path).expect("Error loading theme file.");
+            for warning in warnings {
+                eprintln!("Warning: {warning}");
+            }
+            theme
+        },
+        None => Theme::default(),
+    };
+
+    startup::main_interface(vm, code_capture, theme, args.history_len, args.strict_input, args.start_running, args.ansi_colors).expect("Something went wrong running the program!");
 }  
 
 fn get_file_path() -> String {
@@ -48,10 +258,46 @@ fn get_file_path() -> String {
     buffer
 }
 
-fn sequence_decypher(input:&str) -> Vec<u16> {
-    let words = input.len()/4;
-    (0..words).into_iter().map(|start|{
+/// Turn the escapes in input given on the command line into the characters they stand for.
+/// A backslash before anything else is kept as it is.
+fn unescape_input(input:&str) -> String {
+    let mut result = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            },
+            ('\\', Some('\\')) => {
+                result.push('\\');
+                chars.next();
+            },
+            (other, _) => result.push(other),
+        }
+    }
+    result
+}
+
+fn parse_hex_address(input:&str) -> Result<u16,String> {
+    let digits = input.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|e| format!("'{input}' is not a hexadecimal address: {e}"))
+}
+
+/// Turn a string of hexadecimal digits into words, four digits to a word.
+fn sequence_decypher(input:&str) -> Result<Vec<u16>,String> {
+    if let Some((position,ch)) = input.char_indices().find(|(_,ch)| !ch.is_ascii_hexdigit()) {
+        return Err(format!("'{ch}' at position {position} is not a hexadecimal digit"));
+    }
+    if !input.len().is_multiple_of(4) {
+        return Err(format!("{} digits is not a multiple of 4 (16 bits per word)",input.len()));
+    }
+    if input.len() / 4 > ADDRESS_SPACE {
+        return Err(format!("{} words is more than the {ADDRESS_SPACE} words a program can address",input.len() / 4));
+    }
+    Ok((0..input.len()/4).map(|start|{
         let left = start * 4;
-        u16::from_str_radix(&input[left..left+4], 16).expect("Malformed sequence input!")
-    }).collect()
+        //Checked above that every character is a hexadecimal digit.
+        u16::from_str_radix(&input[left..left+4], 16).unwrap_or_default()
+    }).collect())
 }
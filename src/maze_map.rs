@@ -0,0 +1,270 @@
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash,Hasher};
+use std::io::{self,BufWriter,Write};
+
+use crate::interface::{VmInterface,RuntimeState,ProgramStep,MemoryView,text_input};
+use crate::machine::VirtualMachine;
+
+/// What the game says about the room the player is in: its name and the ways out.
+#[derive(Debug,Clone,PartialEq)]
+pub struct RoomReport {
+    pub title:String,
+    pub exits:Vec<String>,
+}
+
+/// Find the last room description in some output of the game. A room starts with a
+/// `== Name ==` line and lists its exits as `- direction` lines, after a line saying how
+/// many exits there are.
+pub fn parse_room(output:&str) -> Option<RoomReport> {
+    let lines:Vec<&str> = output.lines().map(str::trim).collect();
+    let header = lines.iter().rposition(|line| line.len() > 4 && line.starts_with("== ") && line.ends_with(" =="))?;
+    let title = String::from(&lines[header][3..lines[header].len()-3]);
+    let mut exits = Vec::new();
+    let mut in_exits = false;
+    for line in &lines[header+1..] {
+        if line.starts_with("There are") && line.ends_with("exits:") || *line == "There is 1 exit:" {
+            in_exits = true;
+        } else if in_exits {
+            match line.strip_prefix("- ") {
+                Some(exit) => exits.push(String::from(exit)),
+                None => in_exits = false,
+            }
+        }
+    }
+    Some(RoomReport { title, exits })
+}
+
+/// Gives the VM some commands to type, collects everything it outputs, and stops it as
+/// soon as it asks for input after the last command, or halts.
+struct MapDriver {
+    commands:VecDeque<String>,
+    output:String,
+    out_of_input:bool,
+    finished:bool,
+}
+
+impl MapDriver {
+    fn new(commands:Vec<String>) -> Self {
+        Self { commands: commands.into(), output: String::new(), out_of_input: false, finished: false }
+    }
+}
+
+impl VmInterface for MapDriver {
+    fn write_output(&mut self, c:char) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn write_steps(&mut self, _steps:Vec<ProgramStep>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_memory_view(&mut self, _view:MemoryView) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn runtime_err(&mut self, message:String) {
+        eprintln!("[VM error] {message}");
+    }
+
+    fn set_finished(&mut self, finished:bool) {
+        self.finished = finished;
+    }
+
+    fn set_running(&mut self, _running:bool) {}
+
+    fn set_queued_input(&mut self, _count:usize) {}
+
+    fn read_input(&mut self) -> Vec<u16> {
+        match self.commands.pop_front() {
+            Some(command) => text_input(&format!("{command}\n")),
+            None => {
+                self.out_of_input = true;
+                Vec::new()
+            },
+        }
+    }
+
+    fn input_ended(&self) -> bool {
+        //Running out is how exploring a step ends; read_state stops the VM for it.
+        false
+    }
+
+    fn read_state(&mut self, blocking:bool) -> Option<RuntimeState> {
+        if self.out_of_input || self.finished {
+            Some(RuntimeState::Terminate)
+        } else if blocking {
+            //Whatever made the VM pause, keep it going until the commands run out.
+            Some(RuntimeState::Run)
+        } else {
+            None
+        }
+    }
+}
+
+/// Rooms found by walking every exit, and where each exit leads.
+#[derive(Debug,Default)]
+pub struct MazeMap {
+    pub rooms:Vec<RoomReport>,
+    /// From room, direction, and the room it led to; None if it didn't end up in a room,
+    /// such as when the move was fatal, or in one past the limit on rooms.
+    pub passages:Vec<(usize,String,Option<usize>)>,
+}
+
+/// Length of the longer of the commands typed to find the input buffer; longer than any
+/// `go <exit>`, so whatever is left of one in the buffer is covered too.
+const PROBE_LEN:usize = 32;
+
+/// Words of memory that depend on the last command typed, such as the game's input buffer.
+/// Found by typing two different commands the game doesn't know, in the same place, and
+/// seeing which words differ afterwards.
+fn input_dependent_words(vm:&VirtualMachine) -> HashSet<usize> {
+    let probe = |command:String| {
+        let mut probe_vm = vm.clone();
+        probe_vm.run_program(&mut MapDriver::new(vec![command]));
+        probe_vm
+    };
+    let short = probe(String::from("?"));
+    let long = probe("?".repeat(PROBE_LEN));
+    (0..short.memory().len().max(long.memory().len()))
+        .filter(|address| short.memory().get(*address) != long.memory().get(*address))
+        .collect()
+}
+
+/// Rooms are told apart by the state of memory after walking into them, not by their
+/// description, since the rooms of a maze tend to all look alike. Words that only hold
+/// the command that led there are left out, or a room would look different for every
+/// way into it.
+fn memory_fingerprint(vm:&VirtualMachine, ignored:&HashSet<usize>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (address,word) in vm.memory().iter().enumerate() {
+        if !ignored.contains(&address) {
+            word.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Play `vm` until it asks for input, then try every exit of every room that can be
+/// reached from there, going no further than `max_rooms` rooms. Every room is explored
+/// from a copy of the VM as it was on entering it, so no way back is needed, and a
+/// passage that loops back to a room already seen ends there.
+pub fn explore(vm:&VirtualMachine, max_rooms:usize) -> MazeMap {
+    let mut map = MazeMap::default();
+    let mut start = vm.clone();
+    let mut driver = MapDriver::new(Vec::new());
+    start.run_program(&mut driver);
+    let Some(first_room) = parse_room(&driver.output) else {
+        return map;
+    };
+    let ignored = input_dependent_words(&start);
+    let mut known:HashMap<u64,usize> = HashMap::from([(memory_fingerprint(&start, &ignored), 0)]);
+    map.rooms.push(first_room);
+    let mut to_explore:VecDeque<(usize,VirtualMachine)> = VecDeque::from([(0, start)]);
+
+    while let Some((room, room_vm)) = to_explore.pop_front() {
+        for exit in map.rooms[room].exits.clone() {
+            let mut next_vm = room_vm.clone();
+            let mut driver = MapDriver::new(vec![format!("go {exit}")]);
+            next_vm.run_program(&mut driver);
+            let destination = match parse_room(&driver.output) {
+                Some(report) => {
+                    let fingerprint = memory_fingerprint(&next_vm, &ignored);
+                    match known.get(&fingerprint) {
+                        Some(seen) => Some(*seen),
+                        None if map.rooms.len() < max_rooms => {
+                            let new_room = map.rooms.len();
+                            known.insert(fingerprint, new_room);
+                            map.rooms.push(report);
+                            to_explore.push_back((new_room, next_vm));
+                            Some(new_room)
+                        },
+                        None => None,
+                    }
+                },
+                None => None,
+            };
+            map.passages.push((room, exit, destination));
+        }
+    }
+    map
+}
+
+/// Write the map as a Graphviz graph, one node per room and one edge per exit.
+pub fn save_map_as_dot(map:&MazeMap, path:&str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file,"digraph rooms {{")?;
+    for (index,room) in map.rooms.iter().enumerate() {
+        writeln!(file,"    r{index} [label=\"{}\"];",room.title.replace('"', "\\\""))?;
+    }
+    writeln!(file,"    unknown [label=\"(no room, or not explored)\", shape=box];")?;
+    for (from,direction,to) in map.passages.iter() {
+        let to = to.map_or(String::from("unknown"), |to| format!("r{to}"));
+        writeln!(file,"    r{from} -> {to} [label=\"{}\"];",direction.replace('"', "\\\""))?;
+    }
+    writeln!(file,"}}")?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_with_one_exit() {
+        let output = "\n== Foothills ==\nYou find yourself standing at the base of a mountain.\n\nThere is 1 exit:\n- south\n\nWhat do you do?\n";
+        assert_eq!(parse_room(output), Some(RoomReport { title: String::from("Foothills"), exits: vec![String::from("south")] }));
+    }
+
+    #[test]
+    fn room_with_several_exits() {
+        let output = "== Twisty passages ==\nYou are in a maze.\n\nThere are 3 exits:\n- north\n- east\n- west\n\nWhat do you do?";
+        let room = parse_room(output).unwrap();
+        assert_eq!(room.title, "Twisty passages");
+        assert_eq!(room.exits, ["north","east","west"]);
+    }
+
+    #[test]
+    fn last_room_in_the_output_is_found() {
+        let output = "== First ==\nThere is 1 exit:\n- north\n\n== Second ==\nThere are 2 exits:\n- south\n- down\n";
+        let room = parse_room(output).unwrap();
+        assert_eq!(room.title, "Second");
+        assert_eq!(room.exits, ["south","down"]);
+        assert_eq!(parse_room("I don't understand; try 'help' for instructions."), None);
+    }
+
+    #[test]
+    fn command_typed_doesnt_change_the_fingerprint() {
+        //Store every line typed at 100 onwards: SET R1 100, IN R0, WMEM R1 R0, ADD R1 R1 1,
+        // EQ R2 R0 10, JF R2 3, JMP 0
+        let program = [1,0x8001,100, 20,0x8000, 16,0x8001,0x8000, 9,0x8001,0x8001,1, 4,0x8002,0x8000,10, 8,0x8002,3, 6,0];
+        let mut vm = VirtualMachine::init_from_sequence(&program);
+        vm.run_program(&mut MapDriver::new(Vec::new()));
+        let ignored = input_dependent_words(&vm);
+        let after = |command:&str| {
+            let mut moved = vm.clone();
+            moved.run_program(&mut MapDriver::new(vec![String::from(command)]));
+            moved
+        };
+        let (north,east) = (after("go north"),after("go east"));
+        assert_ne!(memory_fingerprint(&north, &HashSet::new()), memory_fingerprint(&east, &HashSet::new()));
+        assert_eq!(memory_fingerprint(&north, &ignored), memory_fingerprint(&east, &ignored));
+    }
+
+    #[test]
+    fn quotes_are_escaped_in_the_map() {
+        let map = MazeMap {
+            rooms: vec![RoomReport { title: String::from("The \"Hall\""), exits: vec![String::from("a \"door\"")] }],
+            passages: vec![(0, String::from("a \"door\""), None)],
+        };
+        let path = std::env::temp_dir().join(format!("synapone-test-{}-map.dot",std::process::id()));
+        save_map_as_dot(&map, path.to_str().unwrap()).unwrap();
+        let contents = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        let contents = contents.unwrap();
+        assert!(contents.contains("    r0 [label=\"The \\\"Hall\\\"\"];\n"), "{contents}");
+        assert!(contents.contains("    r0 -> unknown [label=\"a \\\"door\\\"\"];\n"), "{contents}");
+    }
+}
@@ -1,26 +1,52 @@
 use std::thread;
 use std::io;
+use std::panic::{catch_unwind,AssertUnwindSafe};
 
 use crate::ui::{MainUiState,start_ui,stop_ui};
 
 use crate::machine::VirtualMachine;
+use crate::code_capture::CodeCapture;
+use crate::theme::Theme;
 use crate::thread_interface::make_interfaces;
+use crate::interface::{UiInterface,VmInterface,RuntimeState};
+use crate::static_analysis::analyze;
 
+/// Name of the thread the VM runs on. Its panics are reported through the UI, instead of
+/// taking the terminal down with them.
+pub(crate) const VM_THREAD_NAME:&str = "vm";
 
-pub(crate) fn main_interface(mut loaded_data:VirtualMachine)->io::Result<()>{
+pub(crate) fn main_interface(mut loaded_data:VirtualMachine, code_capture:Option<CodeCapture>, theme:Theme, history_len:usize, strict_input:bool, start_running:bool, ansi_colors:bool)->io::Result<()>{
     let mut term = start_ui()?;
-    let (mut ui_interface, vm_interface) = make_interfaces();
-    let mut user_interface = MainUiState::new();
+    let (mut ui_interface, vm_interface) = make_interfaces(strict_input);
+    if start_running {
+        //The VM starts out paused; have this waiting for it as its first command.
+        ui_interface.write_state(RuntimeState::Run)?;
+    }
+    let mut user_interface = MainUiState::new(history_len);
+    user_interface.set_theme(theme);
+    user_interface.set_ansi_colors(ansi_colors);
+    user_interface.set_functions(analyze(loaded_data.memory(), &[]).call_targets());
+    if let Some(capture) = code_capture {
+        user_interface.capture_codes(capture);
+    }
 
-    {
-        thread::spawn( move || {
+    let ui_result = {
+        thread::Builder::new().name(String::from(VM_THREAD_NAME)).spawn( move || {
             //VM thread
             let mut vm_interface = vm_interface;
-            loaded_data.run_program(&mut vm_interface);
-        });
-        user_interface.main_loop(&mut term, &mut ui_interface)?;
-    }
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| loaded_data.run_program(&mut vm_interface))) {
+                let message = payload.downcast_ref::<&str>().map(|text| String::from(*text))
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("no details given"));
+                vm_interface.runtime_err(format!("VM thread crashed: {message}"));
+                vm_interface.set_running(false);
+                vm_interface.set_finished(true);
+            }
+        })?;
+        user_interface.main_loop(&mut term, &mut ui_interface)
+    };
 
+    //Put the terminal back the way it was, even if the UI ran into trouble.
     stop_ui()?;
-    Ok(())
+    ui_result
 }
@@ -1,6 +1,7 @@
-use std::{collections::HashSet, ffi::OsStr, fmt::Display, fs::File, io::Write};
+use std::{collections::{BTreeMap, HashMap, HashSet}, ffi::OsStr, fmt::Display, fs::File, io::{self, BufWriter, Write}};
 
 use crate::instruction::*;
+use crate::config_lines::config_lines;
 use itertools::Itertools;
 
 /// A block of executed code.
@@ -14,11 +15,11 @@ struct ExecBlock{
 
 impl ExecBlock {
     fn new(start:u16,end:u16) -> Self {
-        Self { start: start, end: end }
+        Self { start, end }
     }
 
     fn contains(&self,addr:usize) -> bool {
-        self.start as usize >= addr && self.end as usize <= addr
+        self.start as usize <= addr && addr < self.end as usize
     }
 }
 
@@ -45,6 +46,19 @@ enum JumpType {
     Conditional
 }
 
+impl JumpType {
+    fn name(&self) -> &'static str {
+        match self {
+            JumpType::Fixed => "fixed",
+            JumpType::Call => "call",
+            JumpType::Return => "return",
+            JumpType::Halt => "halt",
+            JumpType::Error => "error",
+            JumpType::Conditional => "conditional",
+        }
+    }
+}
+
 impl TryInto<JumpType> for Operation {
     type Error = ();
 
@@ -78,28 +92,123 @@ struct JumpLabel {
 
 impl Jump {
     fn get_label(&self) -> Option<JumpLabel> {
-        if let Some(target) = self.target {
-            Some(JumpLabel{from:self.from,target:target})
-        } else {
-            None
-        }
+        self.target.map(|target| JumpLabel{from:self.from,target})
     }
 }
 
-enum AnalysisError {
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum AnalysisError {
     GenericError,
     FileAccessError,
     FileWriteError,
 }
 
-fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) -> Result<(),AnalysisError> {
+/// Format of the file written by the analysis.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum OutputFormat {
+    /// Human-readable listing of instructions and data.
+    Listing,
+    /// Machine-readable dump of everything the analysis found.
+    Json,
+}
+
+/// Value of an operand, if it is a literal or a register with a known value.
+fn resolve(value:&ParsedValue, known_registers:&[Option<u16>;8]) -> Option<u16> {
+    match value {
+        ParsedValue::Literal(v) => Some(*v),
+        ParsedValue::Register(r) => known_registers[*r as usize],
+        ParsedValue::Error(_) => None,
+    }
+}
+
+/// Everything found out about a program by simulating its control flow.
+pub struct AnalysisResult {
+    /// Blocks of executable code, sorted and with overlapping blocks merged.
+    exec_blocks:Vec<ExecBlock>,
+    /// Every jump encountered, sorted by the address it jumps from.
+    jump_info:Vec<Jump>,
+    /// Labels for every jump with a known target, sorted by target.
+    known_labels:Vec<JumpLabel>,
+    /// Addresses read from by RMEM, with the addresses of the instructions doing so.
+    read_addresses:HashMap<u16,HashSet<u16>>,
+    /// Addresses written to by WMEM, with the addresses of the instructions doing so.
+    write_addresses:HashMap<u16,HashSet<u16>>,
+    /// Comments keyed by address: notes from the analysis itself, and free text from an
+    /// annotations file.
+    comments:HashMap<u16,String>,
+}
+
+impl AnalysisResult {
+    /// Whether the word at `addr` was found to be executed, either as an instruction or
+    /// as one of its operands.
+    pub fn is_code(&self, addr:u16) -> bool {
+        let index = self.exec_blocks.partition_point(|block| block.end <= addr);
+        self.exec_blocks.get(index).is_some_and(|block| block.contains(addr as usize))
+    }
+
+    /// For every address that is jumped to, read or written, the addresses of the
+    /// instructions that do so, sorted and without duplicates.
+    pub fn references(&self) -> BTreeMap<u16,Vec<u16>> {
+        let mut references:BTreeMap<u16,Vec<u16>> = BTreeMap::new();
+        for jump in self.jump_info.iter() {
+            if let Some(target) = jump.target {
+                references.entry(target).or_default().push(jump.from);
+            }
+        }
+        for (address,sources) in self.read_addresses.iter().chain(self.write_addresses.iter()) {
+            references.entry(*address).or_default().extend(sources);
+        }
+        for sources in references.values_mut() {
+            sources.sort_unstable();
+            sources.dedup();
+        }
+        references
+    }
+
+    /// Every known address that is called as a subroutine, sorted and without duplicates.
+    pub fn call_targets(&self) -> Vec<u16> {
+        self.jump_info.iter()
+            .filter(|jump| matches!(jump.jump_type, JumpType::Call))
+            .filter_map(|jump| jump.target)
+            .sorted()
+            .dedup()
+            .collect()
+    }
+}
+
+/// Simulate the control flow of a program, starting at address 0 and at any additional
+/// addresses given.
+pub fn analyze(program:&[u16], additional_starts:&[u16]) -> AnalysisResult {
+    //Register values can only be tracked through an address no jump goes to, but every
+    // simulation may find jumps that weren't known before, so repeat until it doesn't.
+    let mut entry_points:HashSet<u16> = additional_starts.iter().copied().chain([0]).collect();
+    loop {
+        let (mut analysis, found) = simulate(program, additional_starts, &entry_points);
+        if found.is_subset(&entry_points) {
+            analysis.comments = stack_notes(program, &analysis.exec_blocks, &analysis.call_targets());
+            return analysis;
+        }
+        entry_points.extend(found);
+    }
+}
+
+/// One pass of `analyze`, which forgets any register values on reaching one of
+/// `entry_points`. Also gives every address that was found to be jumped to.
+fn simulate(program:&[u16], additional_starts:&[u16], entry_points:&HashSet<u16>) -> (AnalysisResult,HashSet<u16>) {
     //Step 1: setup.
-    let mut read_addresses:HashSet<u16> = HashSet::new();
-    let mut write_addresses:HashSet<u16> = HashSet::new();
+    let mut read_addresses:HashMap<u16,HashSet<u16>> = HashMap::new();
+    let mut write_addresses:HashMap<u16,HashSet<u16>> = HashMap::new();
     let mut exec_blocks:Vec<ExecBlock> = Vec::new();
     let mut jump_targets:Vec<u16> = Vec::with_capacity(8);
     let mut jump_info:Vec<Jump> = Vec::new();
     jump_targets.push(0);
+    jump_targets.extend_from_slice(additional_starts);
+
+    //Operands past the end of the program can't be known, so they count as invalid.
+    let operand = |address:usize| program.get(address)
+        .map(|word| ParsedValue::from(*word))
+        .unwrap_or(ParsedValue::Error(0));
 
     //Step 2: simulate.
     //Grab a 'waiting' jump target to begin.
@@ -111,9 +220,26 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
             }
         }
         let mut program_counter = block_start as usize;
+        //Register values known for certain at this point in the block; nothing is known
+        // about the registers when entering a block.
+        let mut known_registers:[Option<u16>;8] = [None;8];
         loop {
-            let instruction = Operation::from(program[program_counter]);
-            let operands = instruction.operands();
+            //Execution can also get here some other way, with other values in the registers.
+            if entry_points.contains(&(program_counter as u16)) {
+                known_registers = [None;8];
+            }
+            let Some(word) = program.get(program_counter) else {
+                //Ran off the end of the program; whatever was executed so far is a block.
+                if program_counter > block_start as usize {
+                    exec_blocks.push(ExecBlock::new(block_start, program_counter as u16));
+                }
+                continue 'executable;
+            };
+            let instruction = Operation::from(*word);
+            let operands = match instruction {
+                Operation::Error(_) => 0,
+                _ => instruction.operands(),
+            };
             match instruction {
                 //option 1: end-of-block with no further considerations needed.
                 Operation::Halt | Operation::Ret | Operation::Error(_)=> {
@@ -129,8 +255,8 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
                     //Save the current block, try to add the jump target to the buffer.
                     let end = program_counter as u16 + operands + 1;
                     exec_blocks.push(ExecBlock::new(block_start, end));
-                    let target = ParsedValue::from(program[program_counter + 1]);
-                    if let ParsedValue::Literal(address) = target {
+                    let target = resolve(&operand(program_counter + 1), &known_registers);
+                    if let Some(address) = target {
                         jump_targets.push(address);
                         jump_info.push(Jump { from: program_counter as u16, target: Some(address), jump_type: JumpType::Fixed });
                     } else {
@@ -142,8 +268,8 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
                 Operation::Jf | Operation::Jt => {
                     //Try to add the jump target to the buffer, and continue.
                     // Note that the *second* operand holds the jump target.
-                    let target = ParsedValue::from(program[program_counter+2]);
-                    if let ParsedValue::Literal(address) = target {
+                    let target = resolve(&operand(program_counter + 2), &known_registers);
+                    if let Some(address) = target {
                         jump_targets.push(address);
                         jump_info.push(Jump { from: program_counter as u16, target: Some(address), jump_type: JumpType::Conditional });
                     } else {
@@ -152,29 +278,47 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
                 },
                 Operation::Call => {
                     //Try to add the jump target to the buffer, and continue.
-                    let target = ParsedValue::from(program[program_counter+1]);
-                    if let ParsedValue::Literal(address) = target {
+                    let target = resolve(&operand(program_counter + 1), &known_registers);
+                    if let Some(address) = target {
                         jump_targets.push(address);
                         jump_info.push(Jump { from: program_counter as u16, target: Some(address), jump_type: JumpType::Call });
                     } else {
                         jump_info.push(Jump { from: program_counter as u16, target: None, jump_type: JumpType::Call });
                     }
+                    //The subroutine may change any register.
+                    known_registers = [None;8];
                 },
-                //option 4: memory read.
+                //option 4: memory read. The *second* operand is the address read from.
                 Operation::Rmem => {
-                    let target = ParsedValue::from(program[program_counter+1]);
+                    let target = operand(program_counter + 2);
                     if let ParsedValue::Literal(address) = target {
-                        read_addresses.insert(address);
+                        read_addresses.entry(address).or_default().insert(program_counter as u16);
+                    }
+                    if let ParsedValue::Register(r) = operand(program_counter + 1) {
+                        known_registers[r as usize] = None;
                     }
                 },
                 //option 5: memory write.
                 Operation::Wmem => {
-                    let target = ParsedValue::from(program[program_counter+1]);
+                    let target = operand(program_counter + 1);
                     if let ParsedValue::Literal(address) = target {
-                        write_addresses.insert(address);
+                        write_addresses.entry(address).or_default().insert(program_counter as u16);
+                    }
+                },
+                //option 6: a register gets a value that is known.
+                Operation::Set => {
+                    if let ParsedValue::Register(r) = operand(program_counter + 1) {
+                        known_registers[r as usize] = resolve(&operand(program_counter + 2), &known_registers);
+                    }
+                },
+                //option 7: a register gets a value that isn't known.
+                Operation::Pop | Operation::Eq | Operation::Gt | Operation::Add | Operation::Mult |
+                Operation::Mod | Operation::And | Operation::Or | Operation::Not | Operation::In => {
+                    if let ParsedValue::Register(r) = operand(program_counter + 1) {
+                        known_registers[r as usize] = None;
                     }
                 },
-                //option 6: anything else, IDC.
+                //option 8: anything else, IDC.
                 _ => {}
             }
             //Continue on the next operation. Increment program counter, then skip over however
@@ -183,24 +327,18 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
         }
     }
 
-    //Step 3: prepare to write out.
-    //For now, keep only the fixed-target jumps and discard anything that doesn't have a target address.
-    let mut targeted_jumps:Vec<Jump> = jump_info.into_iter()
-        .filter(|jump| jump.target.is_some())
+    //Step 3: organize the results.
+    //Code reachable along several paths gets simulated more than once; only keep one of each jump.
+    jump_info.sort_by_key(|jump| jump.from);
+    jump_info.dedup_by_key(|jump| jump.from);
+    //Keep only the jumps with a known target as labels, sorted based on destination address.
+    let known_labels:Vec<JumpLabel> = jump_info.iter()
+        .filter_map(|jmp| jmp.get_label())
+        .sorted_by_key(|label| label.target)
         .collect();
-    //sort based on destination address, so the data can be used to make labels.
-    targeted_jumps.sort_by(|a,b|
-        a.target
-        .as_ref()
-        .unwrap()
-        .cmp(b.target
-            .as_ref()
-            .unwrap()));
-    let known_labels:Vec<JumpLabel> = targeted_jumps.iter().filter_map(|jmp| jmp.get_label()).collect();
     //Deduplicate and combine the execution blocks, to identify non-executable data.
-    //Sort in reverse.
-    exec_blocks.sort_by(|a,b| b.start.cmp(&a.start));
-    
+    exec_blocks.sort();
+
     let exec_blocks:Vec<ExecBlock> = exec_blocks.into_iter().coalesce(|l,r| {
         if l.end < r.start {
             Err((l,r))
@@ -211,15 +349,364 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
         }
     }).collect();
 
-    let mut destination_file = File::create(save_path).or(Err(AnalysisError::FileAccessError))?;
+    let found = jump_info.iter().filter_map(|jump| jump.target).collect();
+    (AnalysisResult { exec_blocks, jump_info, known_labels, read_addresses, write_addresses, comments: HashMap::new() }, found)
+}
+
+/// Count what every block of code pushes and pops, and note where it doesn't follow the
+/// usual conventions: a POP that takes the return address off the stack at the start of
+/// a subroutine, a RET with the stack not back where the block started, so it returns
+/// somewhere other than to its caller, or a jump or HALT that leaves values behind. A
+/// block here runs from the start of executable code, or from just after a JMP, RET or
+/// HALT, up to the next one; a CALL is taken to leave the stack as it was, and the code
+/// skipped by a conditional jump is counted along with the rest.
+fn stack_notes(program:&[u16], exec_blocks:&[ExecBlock], subroutines:&[u16]) -> HashMap<u16,String> {
+    let mut notes = HashMap::new();
+    for exec_block in exec_blocks {
+        let mut address = exec_block.start as usize;
+        let mut start = address;
+        let mut depth:isize = 0;
+        while exec_block.contains(address) {
+            let Some(word) = program.get(address) else {
+                break;
+            };
+            let instruction = Operation::from(*word);
+            let mut block_ends = false;
+            match instruction {
+                Operation::Push => depth += 1,
+                Operation::Pop => {
+                    depth -= 1;
+                    if depth == -1 && subroutines.contains(&(start as u16)) {
+                        notes.insert(address as u16, format!("stack: takes the return address of the subroutine at {start:04x}"));
+                    }
+                },
+                Operation::Ret => {
+                    if depth != 0 {
+                        notes.insert(address as u16, format!("stack: returns with {depth:+} values compared to entering {start:04x}, not to its caller"));
+                    }
+                    block_ends = true;
+                },
+                Operation::Jmp | Operation::Halt => {
+                    if depth > 0 {
+                        notes.insert(address as u16, format!("stack: leaves {depth} values pushed since entering {start:04x}"));
+                    }
+                    block_ends = true;
+                },
+                Operation::Error(_) => block_ends = true,
+                _ => (),
+            }
+            address += match instruction {
+                Operation::Error(_) => 1,
+                _ => 1 + instruction.operands() as usize,
+            };
+            if block_ends {
+                start = address;
+                depth = 0;
+            }
+        }
+    }
+    notes
+}
+
+/// Read an annotations file of `address ; comment text` lines, with the address in hex.
+/// Empty lines and lines starting with `#` or `;` are skipped. Several comments for the same
+/// address are joined into one.
+pub fn load_annotations(path:&str) -> io::Result<HashMap<u16,String>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut comments:HashMap<u16,String> = HashMap::new();
+    for (number,line) in config_lines(&contents) {
+        let parsed = line.split_once(';')
+            .and_then(|(address,text)| u16::from_str_radix(address.trim(),16).ok().map(|address| (address,text.trim())));
+        let Some((address,text)) = parsed else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{path}:{number}: expected 'address ; comment', got '{line}'")));
+        };
+        comments.entry(address)
+            .and_modify(|existing| {existing.push_str("; "); existing.push_str(text);})
+            .or_insert_with(|| text.to_string());
+    }
+    Ok(comments)
+}
+
+pub fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr,format:OutputFormat,additional_starts:Option<Vec<u16>>,comments:HashMap<u16,String>) -> Result<(),AnalysisError> {
+    if program.is_empty() {
+        return Err(AnalysisError::GenericError);
+    }
+    let mut analysis = analyze(program, &additional_starts.unwrap_or_default());
+    for (address,text) in comments {
+        analysis.comments.entry(address)
+            .and_modify(|existing| {existing.push_str("; "); existing.push_str(&text);})
+            .or_insert(text);
+    }
+    let mut destination_file = BufWriter::new(File::create(save_path).or(Err(AnalysisError::FileAccessError))?);
+    match format {
+        OutputFormat::Listing => write_listing(&mut destination_file, program, original_name, &analysis),
+        OutputFormat::Json => write_json(&mut destination_file, program, original_name, &analysis),
+    }.or(Err(AnalysisError::FileWriteError))?;
+    destination_file.flush().or(Err(AnalysisError::FileWriteError))
+}
+
+/// Compare the analysis of two versions of a program, such as one before and after
+/// patching, and write what changed: blocks of code that appeared or went away,
+/// instructions that were changed, removed or added, and jumps whose target moved. The
+/// instructions are lined up by what they are rather than where they are, so code that
+/// was moved by an insertion isn't listed as changed.
+pub fn diff_programs_and_save(original:&[u16], original_name:&str, patched:&[u16], patched_name:&str, save_path:&OsStr, additional_starts:&[u16]) -> Result<(),AnalysisError> {
+    if original.is_empty() || patched.is_empty() {
+        return Err(AnalysisError::GenericError);
+    }
+    let before = analyze(original, additional_starts);
+    let after = analyze(patched, additional_starts);
+    let mut destination_file = BufWriter::new(File::create(save_path).or(Err(AnalysisError::FileAccessError))?);
+    write_diff(&mut destination_file, (original, original_name, &before), (patched, patched_name, &after))
+        .or(Err(AnalysisError::FileWriteError))?;
+    destination_file.flush().or(Err(AnalysisError::FileWriteError))
+}
+
+/// The instruction starting at `address` as it shows up in a listing, and its length in words.
+fn instruction_text(program:&[u16], address:usize) -> (String,usize) {
+    let instr = Operation::from(program[address]);
+    let operands = match instr {
+        Operation::Error(_) => 0,
+        _ => instr.operands() as usize,
+    };
+    let mut text = format!("{instr}");
+    for op_address in (address+1..=address+operands).filter(|addr| *addr < program.len()) {
+        text.push_str(&format!(" {}",ParsedValue::from(program[op_address])));
+    }
+    (text, operands + 1)
+}
+
+/// Every instruction in the blocks of code of a program, in order, with its address and
+/// how it shows up in a listing.
+fn code_instructions(program:&[u16], analysis:&AnalysisResult) -> Vec<(usize,String)> {
+    let mut instructions = Vec::new();
+    for block in analysis.exec_blocks.iter() {
+        let mut address = block.start as usize;
+        while block.contains(address) && address < program.len() {
+            let (text,length) = instruction_text(program, address);
+            instructions.push((address,text));
+            address += length;
+        }
+    }
+    instructions
+}
+
+/// Most entries in the table used to line up the parts of two programs that differ. Past
+/// this, those parts are listed as removed and added as a whole.
+const MAX_ALIGNMENT_CELLS:usize = 1 << 24;
+
+/// Line up two lists along the longest sequence of items they have in common. Gives the
+/// index into each list for every item, or None on the side of the list it isn't in.
+fn align<T:PartialEq>(old:&[T], new:&[T]) -> Vec<(Option<usize>,Option<usize>)> {
+    //A patch usually leaves most of a program alone, so only the middle needs a table.
+    let prefix = old.iter().zip(new).take_while(|(l,r)| l == r).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(l,r)| l == r).count();
+    let old_middle = &old[prefix..old.len()-suffix];
+    let new_middle = &new[prefix..new.len()-suffix];
+    let (rows,columns) = (old_middle.len(),new_middle.len());
+
+    let mut pairs:Vec<(Option<usize>,Option<usize>)> = (0..prefix).map(|index| (Some(index),Some(index))).collect();
+    let (mut row,mut column) = (0,0);
+    if (rows + 1) * (columns + 1) <= MAX_ALIGNMENT_CELLS {
+        //Entry (row, column) is the length of the longest common sequence of
+        // old_middle[row..] and new_middle[column..].
+        let cell = |row:usize, column:usize| row * (columns + 1) + column;
+        let mut lengths = vec![0u16; (rows + 1) * (columns + 1)];
+        for r in (0..rows).rev() {
+            for c in (0..columns).rev() {
+                lengths[cell(r,c)] = if old_middle[r] == new_middle[c] {
+                    lengths[cell(r+1,c+1)] + 1
+                } else {
+                    lengths[cell(r+1,c)].max(lengths[cell(r,c+1)])
+                };
+            }
+        }
+        while row < rows && column < columns {
+            if old_middle[row] == new_middle[column] {
+                pairs.push((Some(prefix + row),Some(prefix + column)));
+                row += 1;
+                column += 1;
+            } else if lengths[cell(row+1,column)] >= lengths[cell(row,column+1)] {
+                pairs.push((Some(prefix + row),None));
+                row += 1;
+            } else {
+                pairs.push((None,Some(prefix + column)));
+                column += 1;
+            }
+        }
+    }
+    pairs.extend((row..rows).map(|r| (Some(prefix + r),None)));
+    pairs.extend((column..columns).map(|c| (None,Some(prefix + c))));
+    pairs.extend((0..suffix).map(|index| (Some(old.len() - suffix + index),Some(new.len() - suffix + index))));
+    pairs
+}
 
-    writeln!(&mut destination_file,"Data listing for file {original_name}").or(Err(AnalysisError::FileWriteError))?;
-    writeln!(&mut destination_file,"Binary size: {} bytes ({} words)",program.len()*2,program.len()).or(Err(AnalysisError::FileWriteError))?;
-    writeln!(&mut destination_file,"\n\n").or(Err(AnalysisError::FileWriteError))?;
+fn write_diff(destination_file:&mut impl Write, before:(&[u16],&str,&AnalysisResult), after:(&[u16],&str,&AnalysisResult)) -> io::Result<()> {
+    let (original, original_name, old) = before;
+    let (patched, patched_name, new) = after;
+    writeln!(destination_file,"Differences from {original_name} ({} words) to {patched_name} ({} words)",original.len(),patched.len())?;
 
-    let mut exec_blocks = exec_blocks.iter();
+    writeln!(destination_file,"\nBlocks of code:")?;
+    let mut blocks_changed = false;
+    for block in old.exec_blocks.iter().filter(|block| !new.exec_blocks.contains(block)) {
+        writeln!(destination_file,"  - {:0>4x} to {:0>4x}",block.start,block.end)?;
+        blocks_changed = true;
+    }
+    for block in new.exec_blocks.iter().filter(|block| !old.exec_blocks.contains(block)) {
+        writeln!(destination_file,"  + {:0>4x} to {:0>4x}",block.start,block.end)?;
+        blocks_changed = true;
+    }
+    if !blocks_changed {
+        writeln!(destination_file,"  unchanged")?;
+    }
+
+    writeln!(destination_file,"\nInstructions:")?;
+    let old_code = code_instructions(original, old);
+    let new_code = code_instructions(patched, new);
+    let mut instructions_changed = false;
+    //Where each instruction of the original ended up in the patched program, if anywhere.
+    let mut moved_to:HashMap<u16,u16> = HashMap::new();
+    //Instructions that don't line up with anything, since the last ones that did.
+    let mut removed:Vec<&(usize,String)> = Vec::new();
+    let mut added:Vec<&(usize,String)> = Vec::new();
+    let texts = |code:&[(usize,String)]| code.iter().map(|(_,text)| text.clone()).collect::<Vec<_>>();
+    let alignment = align(&texts(&old_code), &texts(&new_code));
+    for pair in alignment.into_iter().map(Some).chain([None]) {
+        if let Some((Some(old_index),None)) = pair {
+            removed.push(&old_code[old_index]);
+            continue;
+        } else if let Some((None,Some(new_index))) = pair {
+            added.push(&new_code[new_index]);
+            continue;
+        }
+        //What was removed and added in between: instructions of the same kind are taken
+        // to be changed, the rest to be removed or added.
+        let mnemonics = |code:&[&(usize,String)]| code.iter().map(|(_,text)| text.split_whitespace().next().unwrap_or_default().to_string()).collect::<Vec<_>>();
+        for change in align(&mnemonics(&removed), &mnemonics(&added)) {
+            match change {
+                (Some(old_index),Some(new_index)) => {
+                    let ((old_address,old_text),(new_address,new_text)) = (removed[old_index],added[new_index]);
+                    moved_to.insert(*old_address as u16, *new_address as u16);
+                    writeln!(destination_file,"  {old_address:0>4x} {old_text}\n    -> {new_address:0>4x} {new_text}")?;
+                },
+                (Some(old_index),None) => writeln!(destination_file,"  - {:0>4x} {}",removed[old_index].0,removed[old_index].1)?,
+                (None,Some(new_index)) => writeln!(destination_file,"  + {:0>4x} {}",added[new_index].0,added[new_index].1)?,
+                (None,None) => (),
+            }
+            instructions_changed = true;
+        }
+        removed.clear();
+        added.clear();
+        if let Some((Some(old_index),Some(new_index))) = pair {
+            moved_to.insert(old_code[old_index].0 as u16, new_code[new_index].0 as u16);
+        }
+    }
+    if !instructions_changed {
+        writeln!(destination_file,"  unchanged")?;
+    }
+
+    writeln!(destination_file,"\nJumps:")?;
+    let mut jumps_changed = false;
+    let new_targets:HashMap<u16,Option<u16>> = new.jump_info.iter().map(|jump| (jump.from,jump.target)).collect();
+    //Addresses in the patched program of the instructions that were in the original.
+    let kept:HashSet<u16> = moved_to.values().copied().collect();
+    let show = |target:Option<u16>| target.map_or(String::from("unknown"), |target| format!("{target:0>4x}"));
+    //Jumps are lined up along with the instructions, and so are their targets.
+    for jump in old.jump_info.iter() {
+        let expected = jump.target.map(|target| *moved_to.get(&target).unwrap_or(&target));
+        match moved_to.get(&jump.from).and_then(|from| new_targets.get(from).map(|target| (from,target))) {
+            Some((_,target)) if *target == expected => (),
+            Some((from,target)) => {
+                writeln!(destination_file,"  {from:0>4x} now goes to {} instead of {}",show(*target),show(jump.target))?;
+                jumps_changed = true;
+            },
+            None => {
+                writeln!(destination_file,"  - {:0>4x} to {}",jump.from,show(jump.target))?;
+                jumps_changed = true;
+            },
+        }
+    }
+    for jump in new.jump_info.iter().filter(|jump| !kept.contains(&jump.from)) {
+        writeln!(destination_file,"  + {:0>4x} to {}",jump.from,show(jump.target))?;
+        jumps_changed = true;
+    }
+    if !jumps_changed {
+        writeln!(destination_file,"  unchanged")?;
+    }
+    Ok(())
+}
+
+/// Shortest run of NOOPs or zero words that is listed as padding, instead of word by word.
+const MIN_PADDING_RUN:usize = 8;
+
+/// Whether a word looks like filler: a NOOP, or nothing at all.
+fn is_padding(word:u16) -> bool {
+    word == 0 || Operation::from(word) == Operation::Noop
+}
+
+/// Write the comments for addresses in a run that is listed as a single line, one line
+/// each, so they aren't lost.
+fn write_comments_in(destination_file:&mut impl Write, analysis:&AnalysisResult, run:std::ops::Range<usize>) -> io::Result<()> {
+    for address in run {
+        if let Some(comment) = analysis.comments.get(&(address as u16)) {
+            writeln!(destination_file,"{address:0>4x}  ; {comment}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Shortest run of OUT instructions that gets its text written out as a comment.
+const MIN_PRINTED_RUN:usize = 3;
+
+/// If a run of at least MIN_PRINTED_RUN OUT instructions with literal operands starts at
+/// `start`, the text they print and the address right after the run.
+fn printed_text(program:&[u16], start:usize, analysis:&AnalysisResult) -> Option<(String,usize)> {
+    let mut text = String::new();
+    let mut address = start;
+    let mut count = 0;
+    while analysis.is_code(address as u16) && address + 1 < program.len() && Operation::from(program[address]) == Operation::Out {
+        match ParsedValue::from(program[address + 1]) {
+            ParsedValue::Literal(ch) => text.push(char::from_u32(ch as u32).unwrap_or('\u{fffd}')),
+            _ => break,
+        }
+        count += 1;
+        address += 2;
+    }
+    if count >= MIN_PRINTED_RUN {
+        Some((text,address))
+    } else {
+        None
+    }
+}
+
+/// Character to show for one byte of a data word, if it is printable at all.
+fn data_char(byte:u16) -> char {
+    match char::from_u32((0x7f & byte) as u32) {
+        Some(ch) if !ch.is_ascii_control() => ch,
+        _ => '.',
+    }
+}
+
+fn write_listing(destination_file:&mut impl Write, program:&[u16], original_name:&str, analysis:&AnalysisResult) -> io::Result<()> {
+    let known_labels = &analysis.known_labels;
+
+    writeln!(destination_file,"Data listing for file {original_name}")?;
+    writeln!(destination_file,"Binary size: {} bytes ({} words)",program.len()*2,program.len())?;
+    let functions = analysis.call_targets();
+    if !functions.is_empty() {
+        writeln!(destination_file,"\nFunctions:")?;
+        for chunk in functions.chunks(8) {
+            writeln!(destination_file,"  {}",chunk.iter().map(|address| format!("{address:0>4x}")).join(" "))?;
+        }
+    }
+    writeln!(destination_file,"\n\n")?;
+
+    let mut exec_blocks = analysis.exec_blocks.iter();
     let mut current_block = exec_blocks.next().expect("No block of execution at the start of the program");
     let mut current_address:usize = 0;
+    //End of the run of printing instructions that was last given a comment.
+    let mut printed_until:usize = 0;
 
     while current_address < program.len() {
         //First: determine if this is executable instructions, or data according to the current block.
@@ -227,20 +714,43 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
             //instruction-block. Read one instruction, check for labels, write out.
             let label = known_labels.iter().filter(|label|label.target as usize == current_address).collect::<Vec<_>>();
             for l in label.into_iter() {
-                writeln!(&mut destination_file,"     :l{:0>4x}",l.from).or(Err(AnalysisError::FileWriteError))?;
+                writeln!(destination_file,"     :l{:0>4x}",l.from)?;
+            }
+            //A long run of NOOPs gets a single line, up to the next jump target in it.
+            let noops = (current_address..program.len())
+                .take_while(|addr| current_block.contains(*addr) && Operation::from(program[*addr]) == Operation::Noop)
+                .take_while(|addr| *addr == current_address || !known_labels.iter().any(|label| label.target as usize == *addr))
+                .count();
+            if noops >= MIN_PADDING_RUN {
+                writeln!(destination_file,"{:0>4x} ; padding, {noops} NOOPs",current_address&0xffff)?;
+                write_comments_in(destination_file, analysis, current_address..current_address+noops)?;
+                current_address += noops;
+                continue;
             }
             let instr = Operation::from(program[current_address]);
 
-            write!(&mut destination_file,"{:0>4x} {instr}",current_address&0xffff).or(Err(AnalysisError::FileWriteError))?;
+            write!(destination_file,"{:0>4x} {instr}",current_address&0xffff)?;
 
-            for op in 0..instr.operands() {
-                let op_address = current_address + (op as usize);
+            let operands = match instr {
+                Operation::Error(_) => 0,
+                _ => instr.operands() as usize,
+            };
+            for op_address in (current_address+1..=current_address+operands).filter(|addr| *addr < program.len()) {
                 let parsed_op = ParsedValue::from(program[op_address]);
-                write!(&mut destination_file," {parsed_op}").or(Err(AnalysisError::FileWriteError))?;
+                write!(destination_file," {parsed_op}")?;
             }
-            writeln!(&mut destination_file,"").or(Err(AnalysisError::FileWriteError))?;
+            if current_address >= printed_until {
+                if let Some((text,end)) = printed_text(program, current_address, analysis) {
+                    write!(destination_file," ; \"{}\"",text.escape_default())?;
+                    printed_until = end;
+                }
+            }
+            if let Some(comment) = analysis.comments.get(&(current_address as u16)) {
+                write!(destination_file,"  ; {comment}")?;
+            }
+            writeln!(destination_file)?;
 
-            current_address += (instr.operands() as usize) + 1;
+            current_address += operands + 1;
         } else {
             //data-block. Fetch the next one, then write word-after-word of this block
             // until the start of the next instruction-block.
@@ -248,7 +758,7 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
             let stop_point;
             if let Some(blk) = another_block {
                 current_block = blk;
-                stop_point = blk.end as usize;
+                stop_point = (blk.start as usize).min(program.len());
             } else {
                 //Write-out until end of file.
                 stop_point = program.len();
@@ -260,46 +770,105 @@ fn parse_program_and_save(program:&[u16],original_name:&str,save_path:&OsStr) ->
             //per example:
             //023B: 6162 4344 6566 4748 6970 5152 7374 5556 | abCDefGHijKLmnOP
 
+            let gap = &program[current_address.min(stop_point)..stop_point];
+            if gap.len() >= MIN_PADDING_RUN && gap.iter().all(|word| is_padding(*word)) {
+                writeln!(destination_file,"{current_address:0>4x} ; padding, {} words",gap.len())?;
+                write_comments_in(destination_file, analysis, current_address..stop_point)?;
+                current_address = stop_point;
+                continue;
+            }
+
             for block_start in (current_address..stop_point).step_by(8) {
                 if stop_point - block_start < 8 {
                     //Handle last (shorter) block.
                     let block_data = &program[block_start..stop_point];
                     let empties = 8 - (stop_point - block_start); //number of words that this block misses, and should be left empty.
-                    write!(&mut destination_file,"{block_start:0>4x}: ").or(Err(AnalysisError::FileWriteError))?;
+                    write!(destination_file,"{block_start:0>4x}: ")?;
                     for word in block_data.iter() {
-                        write!(&mut destination_file,"{:0>4x} ",word).or(Err(AnalysisError::FileWriteError))?;
+                        write!(destination_file,"{:0>4x} ",word)?;
                     }
                     for _ in 0..empties {
-                        write!(&mut destination_file,"     ").or(Err(AnalysisError::FileWriteError))?;
+                        write!(destination_file,"     ")?;
                     }
-                    write!(&mut destination_file,"| ").or(Err(AnalysisError::FileWriteError))?;
+                    write!(destination_file,"| ")?;
 
                     for word in block_data.iter() {
-                        let l = char::from_u32((0x7f & *word) as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
-                        let r = char::from_u32((0x7f & (*word >> 8)) as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
-                        write!(&mut destination_file,"{l}{r}").or(Err(AnalysisError::FileWriteError))?;
+                        let l = data_char(*word);
+                        let r = data_char(*word >> 8);
+                        write!(destination_file,"{l}{r}")?;
                     }
                     //No need to pad the end out. Still need a newline though, so empty writeln. 
-                    writeln!(&mut destination_file,"").or(Err(AnalysisError::FileWriteError))?;
+                    writeln!(destination_file)?;
                 } else {
                     //Handle full block.
                     let block_data = &program[block_start..(block_start+8)];
                     let block_letters = String::from_iter(block_data.iter() //Take the words from the current block...
-                        .map(|num| [0x7f & *num, 0x7f & (*num >> 8)]) //... split the 16-bit word into a pair of 8-bit characters in an array...
-                        .flatten() //...flatten the two-wide arrays into a single sequence of bytes (presented as u16's still)...
-                        .map(|num| char::from_u32(num as u32).unwrap_or(char::REPLACEMENT_CHARACTER))); //...and cast them to characters (or use the default replacement character � if that is not possible.)
+                        .flat_map(|num| [*num, *num >> 8]) //... split each 16-bit word into a pair of 8-bit characters, flattened into a single sequence of bytes (presented as u16's still)...
+                        .map(data_char)); //...and cast them to characters (or a '.' if they are not printable.)
 
-                    writeln!(&mut destination_file,"{block_start:0>4x}: {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} | {}",
+                    writeln!(destination_file,"{block_start:0>4x}: {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} {:0>4x} | {}",
                         block_data[0],block_data[1],block_data[2],block_data[3],block_data[4],block_data[5],block_data[6],block_data[7], block_letters
-                    ).or(Err(AnalysisError::FileWriteError))?;
+                    )?;
                 }
             }
+            current_address = current_address.max(stop_point);
         }
     }
 
-    
     Ok(())
 }
+
+/// Quote a string for use in JSON.
+fn json_string(text:&str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}",c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn write_json(destination_file:&mut impl Write, program:&[u16], original_name:&str, analysis:&AnalysisResult) -> io::Result<()> {
+    writeln!(destination_file,"{{")?;
+    writeln!(destination_file,"  \"header\": {{\"name\": {}, \"size_bytes\": {}, \"size_words\": {}}},",
+        json_string(original_name),program.len()*2,program.len())?;
+
+    let blocks = analysis.exec_blocks.iter()
+        .map(|block| format!("{{\"start\": {}, \"end\": {}}}",block.start,block.end))
+        .join(", ");
+    writeln!(destination_file,"  \"exec_blocks\": [{blocks}],")?;
+
+    let labels = analysis.known_labels.iter()
+        .map(|label| format!("{{\"from\": {}, \"target\": {}}}",label.from,label.target))
+        .join(", ");
+    writeln!(destination_file,"  \"labels\": [{labels}],")?;
+
+    let reads = analysis.read_addresses.keys().sorted().join(", ");
+    writeln!(destination_file,"  \"read_addresses\": [{reads}],")?;
+    let writes = analysis.write_addresses.keys().sorted().join(", ");
+    writeln!(destination_file,"  \"write_addresses\": [{writes}],")?;
+
+    //JSON only allows strings as keys, so the target addresses are quoted.
+    let references = analysis.references().iter()
+        .map(|(target,sources)| format!("\"{target}\": [{}]",sources.iter().join(", ")))
+        .join(",\n    ");
+    writeln!(destination_file,"  \"references\": {{\n    {references}\n  }},")?;
+
+    let jumps = analysis.jump_info.iter()
+        .map(|jump| format!("{{\"from\": {}, \"target\": {}, \"type\": {}}}",
+            jump.from,
+            jump.target.map(|target| target.to_string()).unwrap_or(String::from("null")),
+            json_string(jump.jump_type.name())))
+        .join(",\n    ");
+    writeln!(destination_file,"  \"jumps\": [\n    {jumps}\n  ]")?;
+    writeln!(destination_file,"}}")
+}
 /*
 fn find_containing_block(blocks:&mut Vec<DataBlock>,address:u16) -> &mut DataBlock {
     blocks.sort();
@@ -311,4 +880,111 @@ fn find_containing_block(blocks:&mut Vec<DataBlock>,address:u16) -> &mut DataBlo
         }
     }
     blocks.get_mut(retval).unwrap()
-}*/
\ No newline at end of file
+}*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Instructions section of the diff between two programs.
+    fn instruction_diff(original:&[u16], patched:&[u16]) -> String {
+        let mut written = Vec::new();
+        write_diff(&mut written, (original, "original", &analyze(original, &[])), (patched, "patched", &analyze(patched, &[])))
+            .expect("writing to memory can't fail");
+        let text = String::from_utf8(written).unwrap();
+        let start = text.find("Instructions:\n").unwrap() + "Instructions:\n".len();
+        let end = text.find("\nJumps:").unwrap();
+        text[start..end].to_string()
+    }
+
+    /// Target found for the jump at `from`; None if it isn't known.
+    fn jump_target(program:&[u16], from:u16) -> Option<u16> {
+        analyze(program, &[]).jump_info.iter().find(|jump| jump.from == from).and_then(|jump| jump.target)
+    }
+
+    #[test]
+    fn register_set_before_a_jump_is_its_target() {
+        let program = [1,0x8000,5, 6,0x8000, 0];
+        assert_eq!(jump_target(&program, 3), Some(5));
+    }
+
+    #[test]
+    fn register_is_forgotten_where_another_jump_lands() {
+        //The JMP at 0009 goes to 000d when falling through, but the JT skips the second SET.
+        let program = [1,0x8000,0x0c, 7,0x8001,9, 1,0x8000,0x0d, 6,0x8000, 0, 0, 0];
+        assert_eq!(jump_target(&program, 9), None);
+    }
+
+    #[test]
+    fn comments_in_padding_are_listed() {
+        //JMP past 10 NOOPs and 10 zero words, then HALT. Starting at the NOOPs as well makes
+        // them code, up to the first zero word, which is a HALT.
+        let mut program = vec![6,22];
+        program.extend([21;10]);
+        program.extend([0;10]);
+        program.push(0);
+        let mut analysis = analyze(&program, &[2]);
+        analysis.comments.insert(5, String::from("in the NOOPs"));
+        analysis.comments.insert(15, String::from("in the zeroes"));
+        let mut written = Vec::new();
+        write_listing(&mut written, &program, "padded", &analysis).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        assert!(text.contains("0002 ; padding, 10 NOOPs\n0005  ; in the NOOPs\n"), "{text}");
+        assert!(text.contains("000d ; padding, 9 words\n000f  ; in the zeroes\n"), "{text}");
+    }
+
+    #[test]
+    fn unbalanced_push_in_a_branch() {
+        //CALL 3, HALT; the subroutine returns right away unless R0 is set, in which case it
+        // jumps past the RET to a PUSH before another RET.
+        let program = [17,3, 0, 7,0x8000,7, 18, 2,1, 18];
+        let analysis = analyze(&program, &[]);
+        assert_eq!(analysis.comments.get(&9).map(String::as_str), Some("stack: returns with +1 values compared to entering 0007, not to its caller"));
+        assert_eq!(analysis.comments.len(), 1, "{:?}", analysis.comments);
+    }
+
+    #[test]
+    fn pop_of_the_return_address() {
+        //CALL 3, HALT; POP R0, JMP R0
+        let program = [17,3, 0, 3,0x8000, 6,0x8000];
+        let analysis = analyze(&program, &[]);
+        assert_eq!(analysis.comments.get(&3).map(String::as_str), Some("stack: takes the return address of the subroutine at 0003"));
+    }
+
+    #[test]
+    fn inserted_instruction_doesnt_shift_the_rest() {
+        let original = [19,0x61, 19,0x62, 0];
+        let patched = [21, 19,0x61, 19,0x62, 0];
+        assert_eq!(instruction_diff(&original, &patched), "  + 0000 NOOP\n");
+    }
+
+    #[test]
+    fn removed_instruction_doesnt_shift_the_rest() {
+        let original = [19,0x61, 19,0x62, 19,0x63, 0];
+        let patched = [19,0x61, 19,0x63, 0];
+        assert_eq!(instruction_diff(&original, &patched), "  - 0002 OUT  0062\n");
+    }
+
+    #[test]
+    fn changed_instruction_is_paired_up() {
+        let original = [19,0x61, 19,0x62, 0];
+        let patched = [21, 19,0x61, 19,0x7a, 0];
+        assert_eq!(instruction_diff(&original, &patched), "  + 0000 NOOP\n  0002 OUT  0062\n    -> 0003 OUT  007a\n");
+    }
+
+    #[test]
+    fn jump_moved_with_its_target_is_unchanged() {
+        let original = [6,2, 19,0x61, 0];
+        let patched = [21, 6,3, 19,0x61, 0];
+        let mut written = Vec::new();
+        write_diff(&mut written, (&original, "original", &analyze(&original, &[])), (&patched, "patched", &analyze(&patched, &[]))).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        assert!(text.ends_with("Jumps:\n  unchanged\n"), "{text}");
+        assert!(text.contains("  0000 JMP  0002\n    -> 0001 JMP  0003\n"), "{text}");
+    }
+
+    #[test]
+    fn same_program_has_no_instruction_changes() {
+        let program = [19,0x61, 19,0x62, 0];
+        assert_eq!(instruction_diff(&program, &program), "  unchanged\n");
+    }
+}
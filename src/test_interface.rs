@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+use std::io;
+
+use crate::interface::{VmInterface,RuntimeState,ProgramStep,MemoryView,text_input};
+use crate::machine::VirtualMachine;
+
+/// Drives the VM from a test: hands it a fixed list of commands and pieces of input, and
+/// keeps everything it sends back to look at afterwards. The VM is told to stop once the
+/// commands run out, and input ends for good once the pieces run out.
+#[derive(Default)]
+pub struct TestInterface {
+    commands:VecDeque<RuntimeState>,
+    input:VecDeque<Vec<u16>>,
+    pub output:String,
+    pub steps:Vec<ProgramStep>,
+    pub errors:Vec<String>,
+    pub finished:bool,
+    /// The memory view sent last, if any.
+    pub memory_view:Option<MemoryView>,
+}
+
+impl TestInterface {
+    pub fn new(commands:Vec<RuntimeState>) -> Self {
+        Self { commands: commands.into(), ..Default::default() }
+    }
+
+    /// Answer each request for input from the VM with the next of these pieces of text.
+    pub fn with_input(mut self, pieces:&[&str]) -> Self {
+        self.input = pieces.iter().map(|piece| text_input(piece)).collect();
+        self
+    }
+}
+
+impl VmInterface for TestInterface {
+    fn write_output(&mut self, c:char) -> io::Result<()> {
+        self.output.push(c);
+        Ok(())
+    }
+
+    fn write_steps(&mut self, steps:Vec<ProgramStep>) -> io::Result<()> {
+        self.steps.extend(steps);
+        Ok(())
+    }
+
+    fn write_memory_view(&mut self, view:MemoryView) -> io::Result<()> {
+        self.memory_view = Some(view);
+        Ok(())
+    }
+
+    fn runtime_err(&mut self, message:String) {
+        self.errors.push(message);
+    }
+
+    fn set_finished(&mut self, finished:bool) {
+        self.finished = finished;
+    }
+
+    fn set_running(&mut self, _running:bool) {}
+
+    fn set_queued_input(&mut self, _count:usize) {}
+
+    fn read_input(&mut self) -> Vec<u16> {
+        self.input.pop_front().unwrap_or_default()
+    }
+
+    fn input_ended(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    fn read_state(&mut self, blocking:bool) -> Option<RuntimeState> {
+        if blocking {
+            Some(self.commands.pop_front().unwrap_or(RuntimeState::Terminate))
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the output of two runs of a program first differs.
+#[derive(Debug,PartialEq)]
+pub struct Divergence {
+    /// Number of characters both runs output the same before this point.
+    pub position:usize,
+    /// What each run output there; None if it had stopped printing by then.
+    pub original:Option<char>,
+    pub patched:Option<char>,
+}
+
+/// Run copies of two versions of a program for up to `steps` instructions each, with the
+/// same input, and compare what they output. Meant for checking that a patch changes only
+/// what it was meant to.
+pub fn compare_runs(original:&VirtualMachine, patched:&VirtualMachine, input:&[&str], steps:usize) -> Result<(),Divergence> {
+    let run = |vm:&VirtualMachine| {
+        let mut vm = vm.clone();
+        let commands = if steps > 0 {vec![RuntimeState::RunForSteps(steps)]} else {Vec::new()};
+        let mut interface = TestInterface::new(commands).with_input(input);
+        vm.run_program(&mut interface);
+        interface.output
+    };
+    let original_output = run(original);
+    let patched_output = run(patched);
+    let mut original_chars = original_output.chars();
+    let mut patched_chars = patched_output.chars();
+    let mut position = 0;
+    loop {
+        match (original_chars.next(), patched_chars.next()) {
+            (None, None) => return Ok(()),
+            (original, patched) if original != patched => return Err(Divergence { position, original, patched }),
+            _ => position += 1,
+        }
+    }
+}
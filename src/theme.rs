@@ -0,0 +1,95 @@
+use std::io;
+
+use ratatui::style::{Color,Modifier,Style};
+
+use crate::config_lines::config_lines;
+
+/// Colors used by the UI. Loaded from a file of `key=color-index` lines, where the index
+/// is one of the 256 terminal colors; anything not in the file keeps its default.
+#[derive(Debug,Clone)]
+pub struct Theme {
+    /// The highlighted letter of a command.
+    pub hotkey:Style,
+    /// Ordinary text in the status bar.
+    pub text:Style,
+    /// Text the user is typing.
+    pub input:Style,
+    /// Messages about something that went wrong.
+    pub error:Style,
+    /// The step the program stopped at, on HALT or an error. Shown reversed unless the
+    /// theme gives it colors.
+    pub halted:Style,
+    /// The help screen.
+    pub help:Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            hotkey: Style::new().fg(Color::Blue).bg(Color::White),
+            text: Style::new().fg(Color::White),
+            input: Style::new().fg(Color::Green),
+            error: Style::new().fg(Color::Red),
+            halted: Style::new().add_modifier(Modifier::REVERSED),
+            help: Style::new(),
+        }
+    }
+}
+
+impl Theme {
+    /// Read a theme file. Lines that are empty or start with `#` or `;` are skipped. Unknown keys
+    /// and colors that aren't a number from 0 to 255 don't stop the theme from loading;
+    /// they are returned as warnings instead.
+    pub fn load(path:&str) -> io::Result<(Self,Vec<String>)> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut theme = Self::default();
+        let mut warnings = Vec::new();
+        for (number,line) in config_lines(&contents) {
+            let Some((key,value)) = line.split_once('=') else {
+                warnings.push(format!("{path}:{number}: expected key=color-index, got '{line}'"));
+                continue;
+            };
+            let Ok(index) = value.trim().parse::<u8>() else {
+                warnings.push(format!("{path}:{number}: '{}' is not a color index from 0 to 255",value.trim()));
+                continue;
+            };
+            let color = Color::Indexed(index);
+            match key.trim() {
+                "hotkey_fg" => theme.hotkey = theme.hotkey.fg(color),
+                "hotkey_bg" => theme.hotkey = theme.hotkey.bg(color),
+                "text_fg" => theme.text = theme.text.fg(color),
+                "text_bg" => theme.text = theme.text.bg(color),
+                "input_fg" => theme.input = theme.input.fg(color),
+                "input_bg" => theme.input = theme.input.bg(color),
+                "error_fg" => theme.error = theme.error.fg(color),
+                "error_bg" => theme.error = theme.error.bg(color),
+                "halted_fg" => theme.halted = theme.halted.remove_modifier(Modifier::REVERSED).fg(color),
+                "halted_bg" => theme.halted = theme.halted.remove_modifier(Modifier::REVERSED).bg(color),
+                "help_fg" => theme.help = theme.help.fg(color),
+                "help_bg" => theme.help = theme.help.bg(color),
+                unknown => warnings.push(format!("{path}:{number}: unknown key '{unknown}'")),
+            }
+        }
+        Ok((theme,warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halted_line_drops_reverse_when_colored() {
+        let path = std::env::temp_dir().join(format!("synapone-test-{}-theme.txt",std::process::id()));
+        std::fs::write(&path, "error_fg=9\nhalted_bg=4\nhelp_fg=7\n").unwrap();
+        let loaded = Theme::load(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+        let (theme,warnings) = loaded.unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(theme.error.fg, Some(Color::Indexed(9)));
+        assert_eq!(theme.halted.bg, Some(Color::Indexed(4)));
+        assert!(!theme.halted.add_modifier.contains(Modifier::REVERSED));
+        assert_eq!(theme.help.fg, Some(Color::Indexed(7)));
+        assert!(Theme::default().halted.add_modifier.contains(Modifier::REVERSED));
+    }
+}
@@ -1,28 +1,52 @@
-use std::sync::{mpsc::{self,Sender,Receiver},atomic::{AtomicBool,Ordering},Arc};
-use std::io::{Error,ErrorKind,Result as IoResult};
+use std::sync::{mpsc::{self,Sender,SyncSender,Receiver},atomic::{AtomicBool,AtomicUsize,Ordering},Arc};
+use std::io::{Error,Result as IoResult};
 
 use crate::interface::*;
 
-pub fn make_interfaces() -> (ThreadUiInterface,ThreadVmInterface) {
+/// Batches of steps that may wait for the UI before the VM has to wait for it to catch up.
+/// Keeps a VM that runs faster than the UI can draw from filling up memory.
+const STEP_QUEUE_LEN:usize = 1024;
+
+/// Set up both ends of the connection between the UI and the VM. With `strict_input`,
+/// every submitted piece of input answers exactly one request for input from the VM, in
+/// the order submitted; otherwise a request takes all input that is waiting and
+/// anything sent before the request is thrown away.
+pub fn make_interfaces(strict_input:bool) -> (ThreadUiInterface,ThreadVmInterface) {
     let (state_out,state_in) = mpsc::channel();
     let (input_out,input_in) = mpsc::channel();
     let (output_out,output_in) = mpsc::channel();
-    let (steps_out,steps_in) = mpsc::channel();
+    let (steps_out,steps_in) = mpsc::sync_channel(STEP_QUEUE_LEN);
+    let (errors_out,errors_in) = mpsc::channel();
+    let (memory_out,memory_in) = mpsc::channel();
     let need_input = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    let running = Arc::new(AtomicBool::new(false));
+    let queued_input = Arc::new(AtomicUsize::new(0));
 
     let ui_inter = ThreadUiInterface{
         need_input : need_input.clone(),
+        finished : finished.clone(),
+        running : running.clone(),
+        queued_input : queued_input.clone(),
         state_outgoing : state_out,
         input_outgoing : input_out,
         output_incoming : output_in,
-        steps_incoming : steps_in
+        steps_incoming : steps_in,
+        errors_incoming : errors_in,
+        memory_incoming : memory_in,
     };
     let vm_inter = ThreadVmInterface{
         need_input : need_input.clone(),
+        finished,
+        running,
+        queued_input,
+        strict_input,
         state_incoming : state_in,
         input_incoming : input_in,
         output_outgoing : output_out,
         steps_outgoing : steps_out,
+        errors_outgoing : errors_out,
+        memory_outgoing : memory_out,
     };
     (ui_inter,vm_inter)
 }
@@ -31,19 +55,30 @@ pub fn make_interfaces() -> (ThreadUiInterface,ThreadVmInterface) {
 pub struct ThreadUiInterface {
     /* tbd */
     need_input:Arc<AtomicBool>,
+    finished:Arc<AtomicBool>,
+    running:Arc<AtomicBool>,
+    queued_input:Arc<AtomicUsize>,
     state_outgoing:Sender<RuntimeState>,
-    input_outgoing:Sender<String>,
+    input_outgoing:Sender<Vec<u16>>,
     output_incoming:Receiver<char>,
-    steps_incoming:Receiver<ProgramStep>,
+    steps_incoming:Receiver<Vec<ProgramStep>>,
+    errors_incoming:Receiver<String>,
+    memory_incoming:Receiver<MemoryView>,
 }
 
 pub struct ThreadVmInterface {
     /* tbd */
     need_input: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    queued_input: Arc<AtomicUsize>,
+    strict_input:bool,
     state_incoming:Receiver<RuntimeState>,
-    input_incoming:Receiver<String>,
+    input_incoming:Receiver<Vec<u16>>,
     output_outgoing:Sender<char>,
-    steps_outgoing:Sender<ProgramStep>,
+    steps_outgoing:SyncSender<Vec<ProgramStep>>,
+    errors_outgoing:Sender<String>,
+    memory_outgoing:Sender<MemoryView>,
 }
 
 unsafe impl Send for ThreadUiInterface {}
@@ -61,7 +96,16 @@ impl UiInterface for ThreadUiInterface {
     }
 
     fn read_steps(&mut self) -> Vec<ProgramStep> {
-        Vec::from_iter(self.steps_incoming.try_iter())
+        self.steps_incoming.try_iter().flatten().collect()
+    }
+
+    fn read_errors(&mut self) -> Vec<String> {
+        Vec::from_iter(self.errors_incoming.try_iter())
+    }
+
+    fn read_memory_view(&mut self) -> Option<MemoryView> {
+        //Only the most recent view is of any interest.
+        self.memory_incoming.try_iter().last()
     }
 
     fn need_input(&self) -> bool {
@@ -69,25 +113,42 @@ impl UiInterface for ThreadUiInterface {
     }
 
     fn is_finished(&self) -> bool {
-        //TODO: figure out a way to check if the VM program finished or not.
-        false
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    fn queued_input(&self) -> usize {
+        self.queued_input.load(Ordering::Relaxed)
     }
 
     fn write_input(&mut self, input:&str) -> IoResult<()> {
-        let res = self.input_outgoing.send(String::from(input));
-        match res {
-            Ok(_) => {
-                self.need_input.store(false, Ordering::Relaxed);
-                Ok(())},
-            Err(_) => Err(Error::new(ErrorKind::Other, "Could not send input")),
-        }
+        self.send_input(text_input(input))
+    }
+
+    fn write_input_bytes(&mut self, input:&[u8]) -> IoResult<()> {
+        self.send_input(input.iter().map(|byte| *byte as u16).collect())
     }
 
     fn write_state(&mut self, input:RuntimeState) -> std::io::Result<()> {
         let res = self.state_outgoing.send(input);
         match res {
             Ok(_) => Ok(()),
-            Err(_) => Err(Error::new(ErrorKind::Other, "Could not send state")),
+            Err(_) => Err(Error::other("Could not send state")),
+        }
+    }
+}
+
+impl ThreadUiInterface {
+    fn send_input(&mut self, input:Vec<u16>) -> IoResult<()> {
+        let res = self.input_outgoing.send(input);
+        match res {
+            Ok(_) => {
+                self.need_input.store(false, Ordering::Relaxed);
+                Ok(())},
+            Err(_) => Err(Error::other("Could not send input")),
         }
     }
 }
@@ -96,28 +157,59 @@ impl VmInterface for ThreadVmInterface {
     fn write_output(&mut self, c:char) -> std::io::Result<()> {
         match self.output_outgoing.send(c){
             Ok(_) => Ok(()),
-            Err(e) => Err(Error::new(ErrorKind::Other, e)),
+            Err(e) => Err(Error::other(e)),
+        }
+    }
+
+    fn write_steps(&mut self, steps:Vec<ProgramStep>) -> std::io::Result<()> {
+        match self.steps_outgoing.send(steps){
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::other(e)),
         }
     }
 
-    fn write_step(&mut self, step:ProgramStep) -> std::io::Result<()> {
-        match self.steps_outgoing.send(step){
+    fn write_memory_view(&mut self, view:MemoryView) -> std::io::Result<()> {
+        match self.memory_outgoing.send(view){
             Ok(_) => Ok(()),
-            Err(e) => Err(Error::new(ErrorKind::Other, e)),
+            Err(e) => Err(Error::other(e)),
         }
     }
 
     fn runtime_err(&mut self, s:String) {
-        //Throwing this into the void for now.
-        drop(s);
+        //If the UI is gone, there is nobody left to tell.
+        let _ = self.errors_outgoing.send(s);
+    }
+
+    fn set_finished(&mut self, finished:bool) {
+        self.finished.store(finished, Ordering::Relaxed);
+    }
+
+    fn set_running(&mut self, running:bool) {
+        self.running.store(running, Ordering::Relaxed);
+    }
+
+    fn set_queued_input(&mut self, count:usize) {
+        self.queued_input.store(count, Ordering::Relaxed);
+    }
+
+    fn input_ended(&self) -> bool {
+        //The user can always type more.
+        false
     }
 
-    fn read_input(&mut self) -> String {
+    fn read_input(&mut self) -> Vec<u16> {
+        if self.strict_input {
+            //Hand out input one submission at a time, oldest first.
+            if let Ok(waiting) = self.input_incoming.try_recv() {
+                return waiting;
+            }
+            self.need_input.store(true, Ordering::Relaxed);
+            return self.input_incoming.recv().unwrap_or_default();
+        }
         //Ensure that the channel is empty first, so only the most-recent request for input
         //gets answered
-        let mut clearing = self.input_incoming.try_iter();
-        while let Some(x) = clearing.next() {
-            //Make it explicit that 'junk' Strings are discarded here.
+        for x in self.input_incoming.try_iter() {
+            //Make it explicit that 'junk' input is discarded here.
             drop(x);
         }
 
@@ -128,26 +220,38 @@ impl VmInterface for ThreadVmInterface {
         let input = self.input_incoming.recv();
         match input {
             Ok(s) => {
-                let mut retval = String::from(s);
-                retval.extend(self.input_incoming.try_iter());
+                let mut retval = s;
+                retval.extend(self.input_incoming.try_iter().flatten());
                 retval
             },
-            Err(_) => String::from(""),
+            Err(_) => Vec::new(),
         }
     }
     fn read_state(&mut self, blocking:bool) -> Option<RuntimeState> {
         if blocking {
-            match self.state_incoming.recv() {
-                Ok(s) => Some(s),
-                Err(_) => None,
-            }
+            self.state_incoming.recv().ok()
         } else {
             //not blocking.
-            match self.state_incoming.try_recv() {
-                Ok(s) => Some(s),
-                Err(_) => None,
-            }
+            self.state_incoming.try_recv().ok()
         }
         
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_input_answers_one_request_per_line() {
+        let (mut ui_interface, mut vm_interface) = make_interfaces(true);
+        for line in ["north\n", "take lamp\n", "look\n"] {
+            ui_interface.write_input(line).unwrap();
+        }
+        assert_eq!(vm_interface.read_input(), text_input("north\n"));
+        assert_eq!(vm_interface.read_input(), text_input("take lamp\n"));
+        assert_eq!(vm_interface.read_input(), text_input("look\n"));
+        //Nothing is left over for a later request.
+        assert!(vm_interface.input_incoming.try_recv().is_err());
+    }
+}
@@ -1,31 +1,39 @@
 use std::{
+    collections::{BTreeSet,VecDeque},
     io::{self, stdout, Stdout}, 
     panic::{take_hook,set_hook}, 
-    time::Duration};
+    time::{Duration,Instant}};
 
-use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, MouseButton, MouseEvent, MouseEventKind, EnableMouseCapture, DisableMouseCapture};
 use ratatui::prelude::*;
 use ratatui::symbols::border;
 use ratatui::Frame;
 use ratatui::widgets::{block::*,*};
 use crossterm::{execute, terminal::*};
-use circular_buffer::CircularBuffer;
 
-use crate::interface::{UiInterface,ProgramStep,RegisterState,RuntimeState};
+use crate::interface::{UiInterface,ProgramStep,RegisterState,RuntimeState,MemoryView,trace_to};
+use crate::instruction::{Operation,ParsedValue,assemble,opcode_by_name};
+use crate::code_capture::CodeCapture;
+use crate::theme::Theme;
+use crate::startup::VM_THREAD_NAME;
+use crate::config_lines::config_lines;
+use crate::watch::WatchExpression;
+use crate::ansi::{ESCAPE,sequence_complete,visible_position,styled_lines,StyledTerminal};
 
-const TERMINAL_WIDTH:usize = 100;
+/// Width output is wrapped at until the terminal pane has been drawn once.
+const DEFAULT_TERMINAL_WIDTH:usize = 100;
 
 pub type Tui = Terminal<CrosstermBackend<Stdout>>;
 
 pub fn start_ui() -> io::Result<Tui> {
     setup_panic_hook();
-    execute!(stdout(),EnterAlternateScreen)?;
+    execute!(stdout(),EnterAlternateScreen,EnableMouseCapture)?;
     enable_raw_mode()?;
     Terminal::new(CrosstermBackend::new(stdout()))
 }
 
 pub fn stop_ui() -> io::Result<()> {
-    execute!(stdout(),LeaveAlternateScreen)?;
+    execute!(stdout(),DisableMouseCapture,LeaveAlternateScreen)?;
     disable_raw_mode()?;
     Ok(())
 }
@@ -33,6 +41,10 @@ pub fn stop_ui() -> io::Result<()> {
 pub fn setup_panic_hook() {
     let original_hook = take_hook();
     set_hook(Box::new(move |panic_info| {
+        if std::thread::current().name() == Some(VM_THREAD_NAME) {
+            //The UI is still running; the VM thread tells it what went wrong.
+            return;
+        }
         // intentionally ignore errors here since we're already in a panic
         let _ = stop_ui();
         original_hook(panic_info);
@@ -41,10 +53,70 @@ pub fn setup_panic_hook() {
 
 #[derive(Debug,Default)]
 pub struct MainUiState {
-    prog_states:Box<CircularBuffer<1024,ProgramStep>>,
+    prog_states:VecDeque<ProgramStep>,
+    previous_registers:Option<RegisterState>,
+    history_len:usize,
+    /// Where the instruction history was drawn last, to find out what a mouse click hit.
+    history_area:Rect,
+    /// When steps arrived from the VM and how many, going back RATE_WINDOW.
+    step_arrivals:VecDeque<(Instant,usize)>,
+    memory_view:MemoryView,
     terminal_text:Vec<String>,
+    /// terminal_text with its escape sequences turned into styles, kept between frames.
+    styled_terminal:StyledTerminal,
+    output_cursor:OutputCursor,
+    /// Colors and such in the output, given with ANSI escape sequences, are shown instead of
+    /// the sequences themselves.
+    ansi_colors:bool,
+    /// Characters that fit on a line of the terminal pane, as it was drawn last.
+    terminal_width:usize,
+    /// Everything the program has output, to show the output as it was at an earlier step.
+    raw_output:String,
+    /// Characters at the start of raw_output that were cleared from the terminal, and stay
+    /// hidden when looking back.
+    cleared_output:usize,
+    /// How many steps back from the most recent one the history is scrolled; 0 shows the
+    /// current state.
+    history_offset:usize,
     ui_mode:UiMode,
     input_buffer:String,
+    /// Why what was typed in the input field could not be used; shown below the field
+    /// until the next key press.
+    input_error:Option<String>,
+    /// Lines sent to the VM so far, oldest first.
+    input_history:Vec<String>,
+    /// Entry of the input history currently shown in the input field, if any.
+    history_cursor:Option<usize>,
+    code_capture:Option<CodeCapture>,
+    profiling:bool,
+    tracing:bool,
+    logging:bool,
+    echo_input:bool,
+    /// Addresses the VM was told to pause at.
+    breakpoints:BTreeSet<u16>,
+    /// Opcodes the VM was told to pause after.
+    opcode_breaks:BTreeSet<u16>,
+    /// Expressions over the registers, shown with their values below the upcoming instructions.
+    watches:Vec<WatchExpression>,
+    /// Registers saved to compare later ones against.
+    checkpoint:Option<RegisterState>,
+    /// The register pane shows what changed since the checkpoint, rather than since the previous step.
+    compare_to_checkpoint:bool,
+    /// Step counts are typed in hexadecimal rather than decimal.
+    count_in_hex:bool,
+    /// Start addresses of the subroutines the static analysis found, sorted, to name the
+    /// entries of the call stack.
+    functions:Vec<u16>,
+    show_help:bool,
+    /// The program ran into a HALT instruction; the VM is done.
+    halted:bool,
+    /// The VM is executing instructions, as last reported by it.
+    running:bool,
+    /// Input characters the VM has that the program hasn't read yet.
+    queued_input:usize,
+    /// The last command that moved the VM along was a single step.
+    stepping:bool,
+    theme:Theme,
     exit:bool
 }
 
@@ -53,38 +125,271 @@ enum UiMode {
     #[default]
     Normal,
     WaitingForInput,
+    WaitingForRawInput,
     WaitingForAddress,
     WaitingForCount,
+    WaitingForPokeAddress,
+    WaitingForDumpStart,
+    WaitingForDumpEnd(u16),
+    WaitingForPokeValue(u16),
+    WaitingForFindAddress,
+    WaitingForTraceMark,
+    WaitingForBreakpoint,
+    WaitingForOutputText,
+    WaitingForInjection,
+    WaitingForWatch,
+    WaitingForOpcode,
+    /// Which quick save slot to use; true to load from it, false to save to it.
+    WaitingForSlot(bool),
     InputReady,
+    RawInputReady,
     AddressReady,
     CountReady,
+    PokeReady(u16),
+    WaitingForPath(PathDestination),
+    PathReady(PathDestination),
     Command,
+    ConfirmQuit,
     Paused,
 }
 
+/// What a file path entered by the user is meant for.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum PathDestination {
+    Profile,
+    Trace,
+    OutputLog,
+    Analysis,
+    Transcript,
+    SaveBreakpoints,
+    LoadBreakpoints,
+    /// Dump of the words from the first address up to and including the second.
+    MemoryDump(u16,u16),
+    Binary,
+}
+
 const DEFAULT_STATE:ProgramStep = ProgramStep::const_default();
 const POLL_TIME:Duration = Duration::from_millis(100);
+/// Time over which the instructions-per-second figure is averaged.
+const RATE_WINDOW:Duration = Duration::from_secs(2);
+/// Number of instructions shown ahead of the program counter.
+const UPCOMING_INSTRUCTIONS:usize = 8;
+/// Contents of the help screen.
+const HELP_TEXT:&str = "\
+Normal mode
+  esc     enter command mode
+  ? / F1  show this help
+
+Command mode
+  r       run in normal mode
+  s       single step
+  sh-tab  step over: like s, but a CALL runs until it returns
+  O       step out: run until the current routine returns
+  a       run until address (hexadecimal)
+  n       run for a number of steps
+  o       run until the program outputs some text
+  b       set or remove a breakpoint (hexadecimal address)
+  B       remove all breakpoints
+  y       pause after any instruction of a kind, like OUT or WMEM, or stop doing so
+  Y       stop pausing on kinds of instruction
+  g       show the call stack, named after the subroutines the static analysis finds
+  z       quick save the state of the VM to a slot (0-9), kept until quitting
+  Z       quick load from a slot
+  S       save the breakpoints to a file
+  G       get breakpoints from a saved file, replacing the ones set now
+  p       start profiling / stop and save the profile to a file
+  t       start tracing to a file / stop tracing; a file name ending in .csv gets
+          every instruction in CSV
+  k       write a note into the trace (while tracing)
+  l       start logging output to a file / stop logging
+  e       reset the VM to its initial state
+  i       toggle echoing typed input
+  c       clear the terminal pane
+  m       change a word of memory (address, then value, both hexadecimal)
+  u       undo the most recent change made with m
+  j       execute an instruction typed in, like ADD R0 R1 5 (hexadecimal literals)
+  v       watch a value worked out from the registers, like R0 + R1 or R7 & 7fff
+          (hexadecimal literals, + - & | ^ applied from left to right)
+  V       remove all watches
+  h       save the registers as a checkpoint, and compare the register pane to it
+  H       switch the register pane between comparing to the checkpoint and to the
+          previous step
+  up/down step back and forth through the history, output included
+  end     back to the most recent step
+  f       scroll the history to the last step at an address (hexadecimal)
+  d       disassemble memory to a file
+  w       write a range of memory to a file (first and last address, hexadecimal);
+          words found to be data are left undecoded
+  W       save all of memory as a binary, to run again later
+  x       export everything in the terminal pane to a file
+  q       quit (asks for confirmation)
+  esc     back to normal mode
+
+Typing input for the VM
+  tab     switch between text and raw bytes (hexadecimal, separated by spaces)
+  up/down recall earlier lines
+  enter   send
+
+Mouse
+  click an instruction in the history to run until it is reached again
+
+Entering an address, count or path
+  tab     switch a count between decimal and hexadecimal
+  enter   confirm
+  esc     cancel (anything but input, addresses and counts)
+
+Press esc to close this help.";
 
 impl MainUiState {
-    pub fn new() -> Self{
+    /// Set up the UI, keeping the last `history_len` executed instructions around.
+    pub fn new(history_len:usize) -> Self{
+        let history_len = history_len.max(1);
         Self { 
-            prog_states: CircularBuffer::<1024,ProgramStep>::boxed(), 
+            prog_states: VecDeque::with_capacity(history_len),
+            previous_registers: None,
+            history_len,
+            step_arrivals: VecDeque::new(),
+            history_area: Rect::default(),
+            memory_view: MemoryView::default(),
             terminal_text: Vec::new(),
+            styled_terminal: StyledTerminal::default(),
+            output_cursor: OutputCursor::default(),
+            ansi_colors: false,
+            terminal_width: DEFAULT_TERMINAL_WIDTH,
+            raw_output: String::new(),
+            cleared_output: 0,
+            history_offset: 0,
             ui_mode: UiMode::Normal,
             input_buffer: String::new(),
+            input_error: None,
+            input_history: Vec::new(),
+            history_cursor: None,
+            code_capture: None,
+            profiling: false,
+            tracing: false,
+            logging: false,
+            echo_input: true,
+            breakpoints: BTreeSet::new(),
+            opcode_breaks: BTreeSet::new(),
+            watches: Vec::new(),
+            checkpoint: None,
+            compare_to_checkpoint: false,
+            count_in_hex: false,
+            functions: Vec::new(),
+            show_help: false,
+            halted: false,
+            running: false,
+            queued_input: 0,
+            stepping: false,
+            theme: Theme::default(),
             exit: false 
         }
     }
 
+    /// Average number of instructions executed per second, over the last RATE_WINDOW.
+    fn instructions_per_second(&self) -> f64 {
+        let executed:usize = self.step_arrivals.iter().map(|(_,count)| count).sum();
+        executed as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    /// Keep track of what the VM was last told to do, for the run state indicator.
+    fn note_command(&mut self, command:&RuntimeState) {
+        match command {
+            RuntimeState::SingleStep | RuntimeState::StepOver => self.stepping = true,
+            RuntimeState::Run | RuntimeState::Pause | RuntimeState::RunForSteps(_) |
+            RuntimeState::RunUntilAddress(_) | RuntimeState::RunUntilOutputContains(_) | RuntimeState::StepOut |
+            RuntimeState::Reset => self.stepping = false,
+            _ => (),
+        }
+    }
+
+    /// Base in which step counts are typed.
+    fn count_radix(&self) -> u32 {
+        if self.count_in_hex {16} else {10}
+    }
+
+    /// Short description of what the VM is doing right now.
+    fn run_state_name(&self) -> &'static str {
+        if self.halted {
+            "HALTED"
+        } else if self.running {
+            "RUNNING"
+        } else if self.stepping {
+            "STEPPING"
+        } else {
+            "PAUSED"
+        }
+    }
+
+    /// Show the colors and such that the program asks for with ANSI escape sequences.
+    pub fn set_ansi_colors(&mut self, ansi:bool) {
+        self.ansi_colors = ansi;
+    }
+
+    /// Name the calls on the call stack after these subroutines, found by static analysis.
+    pub fn set_functions(&mut self, functions:Vec<u16>) {
+        self.functions = functions;
+        self.functions.sort_unstable();
+    }
+
+    /// Use different colors than the default ones.
+    pub fn set_theme(&mut self, theme:Theme) {
+        self.theme = theme;
+    }
+
+    /// Scan all output of the VM for challenge codes from here on.
+    pub fn capture_codes(&mut self, capture:CodeCapture) {
+        self.code_capture = Some(capture);
+    }
+
     pub fn main_loop(&mut self, terminal:&mut Tui, input:&mut impl UiInterface) -> io::Result<()> {
         while !self.exit {
             let latest_steps = input.read_steps();
-            self.prog_states.extend(latest_steps);
+            let now = Instant::now();
+            if !latest_steps.is_empty() {
+                self.step_arrivals.push_back((now,latest_steps.len()));
+            }
+            while self.step_arrivals.front().is_some_and(|(arrival,_)| now.duration_since(*arrival) > RATE_WINDOW) {
+                self.step_arrivals.pop_front();
+            }
+            for step in latest_steps {
+                self.previous_registers = self.prog_states.back().map(|previous| previous.registers.clone());
+                if self.prog_states.len() >= self.history_len {
+                    self.prog_states.pop_front();
+                }
+                self.prog_states.push_back(step);
+                if self.history_offset > 0 {
+                    //Stay on the same step while new ones come in.
+                    self.history_offset = (self.history_offset + 1).min(self.prog_states.len() - 1);
+                }
+            }
             if let Some(line) = input.read_output() {
+                self.raw_output.push_str(&line);
+                if let Some(capture) = &mut self.code_capture {
+                    for cr in line.chars() {
+                        //Failing to save a code should not interrupt the session.
+                        let _ = capture.push_char(cr);
+                    }
+                }
                 self.prep_string_input(line);
             }
+            for message in input.read_errors() {
+                self.show_error(message);
+            }
+            if let Some(view) = input.read_memory_view() {
+                self.memory_view = view;
+            }
             
-            if input.is_finished() && self.ui_mode == UiMode::Normal {
+            self.halted = input.is_finished();
+            self.running = input.is_running();
+            self.queued_input = input.queued_input();
+            //No more output is coming for now, so a code right at the end of it is complete.
+            if self.halted || input.need_input() {
+                if let Some(capture) = &mut self.code_capture {
+                    let _ = capture.finish_word();
+                }
+            }
+            if self.halted && self.ui_mode == UiMode::Normal {
                 self.ui_mode = UiMode::Paused;
             } else if input.need_input() && self.ui_mode == UiMode::Normal{
                 self.ui_mode = UiMode::WaitingForInput;
@@ -93,20 +398,131 @@ impl MainUiState {
 
             match self.ui_mode {
                 UiMode::InputReady => {
-                    let terminal_text = format!("> {}\n",&self.input_buffer[..]);
-                    self.terminal_text.push(terminal_text);
-                    input.write_input(&self.input_buffer)?;
+                    if self.echo_input {
+                        let echo = format!("> {}",&self.input_buffer[..]);
+                        self.prep_string_input(echo);
+                    }
+                    if input.write_input(&self.input_buffer).is_err() {
+                        self.show_vm_gone();
+                    }
+                    let line = self.input_buffer.trim_end_matches('\n');
+                    if !line.is_empty() && self.input_history.last().is_none_or(|last| last != line) {
+                        self.input_history.push(String::from(line));
+                    }
+                    self.history_cursor = None;
                     self.ui_mode = UiMode::Normal;
                 },
+                UiMode::RawInputReady => {
+                    let bytes:Result<Vec<u8>,_> = self.input_buffer.split_whitespace()
+                        .map(|byte| u8::from_str_radix(byte, 16))
+                        .collect();
+                    match bytes {
+                        Ok(bytes) => {
+                            if self.echo_input {
+                                let echo = format!("> [raw {}]\n",self.input_buffer.trim());
+                                self.prep_string_input(echo);
+                            }
+                            if input.write_input_bytes(&bytes).is_err() {
+                                self.show_vm_gone();
+                            }
+                            self.ui_mode = UiMode::Normal;
+                        },
+                        Err(e) => {
+                            //Let the user fix the mistake instead of throwing the input away.
+                            self.input_error = Some(format!("Not a list of hexadecimal bytes: {e}"));
+                            self.ui_mode = UiMode::WaitingForRawInput;
+                        },
+                    }
+                },
                 UiMode::AddressReady => {
-                    if let Ok(address) = u16::from_str_radix(&self.input_buffer[..], 16){
-                        input.write_state(RuntimeState::RunUntilAddress(address)).expect("Could not send address to VM");
+                    match u16::from_str_radix(&self.input_buffer[..], 16) {
+                        Ok(address) => {
+                            self.stepping = false;
+                            self.send_state(input, RuntimeState::RunUntilAddress(address));
+                            self.ui_mode = UiMode::Normal;
+                        },
+                        Err(e) => {
+                            self.input_error = Some(format!("Not an address: {e}"));
+                            self.ui_mode = UiMode::WaitingForAddress;
+                        },
                     }
-                    self.ui_mode = UiMode::Normal;
                 },
                 UiMode::CountReady => {
-                    if let Ok(count) = self.input_buffer.parse::<usize>(){
-                        input.write_state(RuntimeState::RunForSteps(count)).expect("Could not send step count to VM");
+                    match usize::from_str_radix(&self.input_buffer[..], self.count_radix()) {
+                        Ok(0) => {
+                            self.input_error = Some(String::from("Not a number of steps: must be at least 1"));
+                            self.ui_mode = UiMode::WaitingForCount;
+                        },
+                        Ok(count) => {
+                            self.stepping = false;
+                            self.send_state(input, RuntimeState::RunForSteps(count));
+                            self.ui_mode = UiMode::Normal;
+                        },
+                        Err(e) => {
+                            self.input_error = Some(format!("Not a number of steps: {e}"));
+                            self.ui_mode = UiMode::WaitingForCount;
+                        },
+                    }
+                },
+                UiMode::PokeReady(address) => {
+                    match u16::from_str_radix(&self.input_buffer[..], 16) {
+                        Ok(value) => {
+                            self.send_state(input, RuntimeState::SetMemory(address, value));
+                            self.ui_mode = UiMode::Normal;
+                        },
+                        Err(e) => {
+                            self.input_error = Some(format!("Not a value for {address:04x}: {e}"));
+                            self.ui_mode = UiMode::WaitingForPokeValue(address);
+                        },
+                    }
+                },
+                UiMode::PathReady(destination) => {
+                    let path = std::mem::take(&mut self.input_buffer);
+                    let state = match destination {
+                        PathDestination::Profile => {
+                            self.profiling = false;
+                            Some(RuntimeState::ProfileStop(path))
+                        },
+                        PathDestination::Trace => {
+                            self.tracing = true;
+                            Some(trace_to(path))
+                        },
+                        PathDestination::OutputLog => {
+                            self.logging = true;
+                            Some(RuntimeState::LogOutput(path))
+                        },
+                        PathDestination::Analysis => Some(RuntimeState::AnalyzeNow(path)),
+                        PathDestination::MemoryDump(start, end) => Some(RuntimeState::DumpMemory(start, end, path)),
+                        PathDestination::Binary => Some(RuntimeState::SaveBinary(path)),
+                        PathDestination::Transcript => {
+                            //Nothing for the VM to do; everything shown is right here.
+                            if let Err(e) = std::fs::write(&path, self.terminal_text.join("\n")) {
+                                self.show_message(format!("[UI error] Could not export transcript: {e}"));
+                            }
+                            None
+                        },
+                        PathDestination::SaveBreakpoints => {
+                            let addresses:String = self.breakpoints.iter().map(|address| format!("{address:04x}\n")).collect();
+                            if let Err(e) = std::fs::write(&path, format!("# Breakpoints, one hexadecimal address per line\n{addresses}")) {
+                                self.show_message(format!("[UI error] Could not save breakpoints: {e}"));
+                            }
+                            None
+                        },
+                        PathDestination::LoadBreakpoints => {
+                            match load_breakpoints(&path) {
+                                Ok(addresses) => {
+                                    self.breakpoints = addresses;
+                                    Some(RuntimeState::SetBreakpoints(self.breakpoints.iter().copied().collect()))
+                                },
+                                Err(e) => {
+                                    self.show_message(format!("[UI error] Could not load breakpoints: {e}"));
+                                    None
+                                },
+                            }
+                        },
+                    };
+                    if let Some(state) = state {
+                        self.send_state(input, state);
                     }
                     self.ui_mode = UiMode::Normal;
                 },
@@ -115,7 +531,8 @@ impl MainUiState {
 
             match self.handle_input() {
                 Ok(Some(x)) => {
-                    input.write_state(x)?;
+                    self.note_command(&x);
+                    self.send_state(input, x);
                 }
                 Ok(None) => (),
                 Err(e) => return Err(e),
@@ -125,51 +542,153 @@ impl MainUiState {
         Ok(())
     }
 
-    fn render_frame(&self, frame:&mut Frame){
+    /// Tell the VM what to do. Once the VM is gone, all that is left is to say so.
+    fn send_state(&mut self, input:&mut impl UiInterface, state:RuntimeState) {
+        if input.write_state(state).is_err() {
+            self.show_vm_gone();
+        }
+    }
+
+    fn show_vm_gone(&mut self) {
+        self.show_message(String::from("[UI error] The VM is no longer running, nothing left to do but quit."));
+    }
+
+    fn render_frame(&mut self, frame:&mut Frame){
         let root_layout = Layout::default()
             .direction(Direction::Vertical)
-            .constraints(vec![Constraint::Length(4),Constraint::Fill(1),Constraint::Length(3)])
+            .constraints(vec![Constraint::Length(4),Constraint::Fill(1),Constraint::Length(4)])
             .split(frame.size());
         let mid_layout = Layout::default()
             .direction(Direction::Horizontal)
-            .constraints(vec![Constraint::Min(47),Constraint::Length(28)])
+            .constraints(vec![Constraint::Min(47),Constraint::Length(40)])
             .split(root_layout[1]);
+        //Room for the watches only when there are any.
+        let watch_height = if self.watches.is_empty() {0} else {self.watches.len() as u16 + 2};
+        let side_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![Constraint::Fill(1),Constraint::Length(UPCOMING_INSTRUCTIONS as u16 + 2),Constraint::Length(watch_height)])
+            .split(mid_layout[1]);
+        self.history_area = side_layout[0];
+        self.terminal_width = (mid_layout[0].width.saturating_sub(2) as usize).max(1);
         let def = DEFAULT_STATE;
-        let current_state = self.prog_states.back().unwrap_or(&def);
+        //When scrolled back, everything is shown as it was at the selected step.
+        let shown_steps = self.prog_states.len() - self.history_offset;
+        let current_state = self.prog_states.get(shown_steps.wrapping_sub(1)).unwrap_or(&def);
+        let previous_registers = if self.history_offset == 0 {
+            self.previous_registers.as_ref()
+        } else {
+            shown_steps.checked_sub(2).and_then(|index| self.prog_states.get(index)).map(|step| &step.registers)
+        };
 
         let instruction_lines:Vec<Line> = self.prog_states.iter()
+            .take(shown_steps)
             .rev()
-            .take((mid_layout[1].height - 2) as usize) // -2 to allow room for the borders around the list.
+            .take(side_layout[0].height.saturating_sub(2) as usize) // -2 to allow room for the borders around the list.
             .rev()
             .map(|state| {
                 let inst_line = format!("{:04x}:{}",state.registers.program_counter,&state.instruction[..]);
-                Line::from(inst_line)
+                //Make where the program stopped stand out.
+                if state.instruction == "HALT" || state.instruction.starts_with("ERROR") {
+                    Line::styled(inst_line, self.theme.halted)
+                } else {
+                    Line::from(inst_line)
+                }
             })
             .collect();
 
-        let terminal_lines:Vec<Line> = self.terminal_text.iter()
-            .rev()
-            .take((mid_layout[0].height - 2) as usize) // See above.
-            .rev()
-            .map(|text| Line::from(&text[..]))
+        let terminal_height = mid_layout[0].height.saturating_sub(2) as usize; // See above.
+        let (terminal_lines,terminal_title):(Vec<Line>,String) = if self.history_offset == 0 {
+            let lines = if self.ansi_colors {
+                self.styled_terminal.last_lines(&self.terminal_text, terminal_height)
+            } else {
+                self.terminal_text.iter()
+                    .rev()
+                    .take(terminal_height)
+                    .rev()
+                    .map(|text| Line::from(&text[..]))
+                    .collect()
+            };
+            (lines, String::from("Terminal"))
+        } else {
+            let byte_index = |chars:usize| self.raw_output.char_indices()
+                .nth(chars)
+                .map_or(self.raw_output.len(), |(index,_)| index);
+            let output_start = byte_index(self.cleared_output.min(current_state.output_len));
+            let output_end = byte_index(current_state.output_len);
+            let lines = output_lines(&self.raw_output[output_start..output_end], self.terminal_width, self.ansi_colors);
+            let lines = if self.ansi_colors {
+                styled_lines(&lines, terminal_height)
+            } else {
+                let skip = lines.len().saturating_sub(terminal_height);
+                lines.into_iter().skip(skip).map(Line::from).collect()
+            };
+            (lines, format!("Terminal, {} steps back",self.history_offset))
+        };
+
+        let checkpoint = self.checkpoint.as_ref().filter(|_| self.compare_to_checkpoint);
+        frame.render_widget(RegisterPane { current: &current_state.registers, previous: previous_registers, checkpoint }, root_layout[0]);
+        frame.render_widget(Paragraph::new(terminal_lines).block(Block::default().title(terminal_title).borders(Borders::ALL).border_set(border::THICK)),mid_layout[0]);
+        frame.render_widget(Paragraph::new(instruction_lines).block(Block::default().title("Instructions").borders(Borders::ALL).border_set(border::THICK)), side_layout[0]);
+        let breaks:Vec<String> = self.breakpoints.iter().map(|address| format!("{address:04x}"))
+            .chain(self.opcode_breaks.iter().map(|opcode| Operation::from(*opcode).to_string().trim_end().to_string()))
             .collect();
+        let upcoming_title = if breaks.is_empty() {
+            String::from("Upcoming")
+        } else {
+            format!("Upcoming, breaks at {}",breaks.join(" "))
+        };
+        frame.render_widget(Paragraph::new(disassemble_view(&self.memory_view, UPCOMING_INSTRUCTIONS)).block(Block::default().title(upcoming_title).borders(Borders::ALL).border_set(border::THICK)), side_layout[1]);
+        if !self.watches.is_empty() {
+            let watch_lines:Vec<Line> = self.watches.iter()
+                .map(|watch| Line::from(format!("{:04x} = {watch}",watch.evaluate(&current_state.registers))))
+                .collect();
+            frame.render_widget(Paragraph::new(watch_lines).block(Block::default().title("Watches").borders(Borders::ALL).border_set(border::THICK)), side_layout[2]);
+        }
+        frame.render_widget(&*self, root_layout[2]);
 
-        frame.render_widget(&current_state.registers, root_layout[0]);
-        frame.render_widget(Paragraph::new(terminal_lines).block(Block::default().title("Terminal").borders(Borders::ALL).border_set(border::THICK)),mid_layout[0]);
-        frame.render_widget(Paragraph::new(instruction_lines).block(Block::default().title("Instructions").borders(Borders::ALL).border_set(border::THICK)), mid_layout[1]);
-        frame.render_widget(self, root_layout[2]);
+        if self.ui_mode == UiMode::ConfirmQuit {
+            let quit_area = centered_rect(frame.size(), 20, 3);
+            frame.render_widget(ratatui::widgets::Clear, quit_area);
+            frame.render_widget(Paragraph::new("Quit? (y/n)").alignment(Alignment::Center).block(Block::default().borders(Borders::ALL).border_set(border::THICK)), quit_area);
+        }
+        if self.show_help {
+            let help_lines = HELP_TEXT.lines().count() as u16 + 2;
+            let help_area = centered_rect(frame.size(), 72, help_lines);
+            frame.render_widget(ratatui::widgets::Clear, help_area);
+            frame.render_widget(Paragraph::new(HELP_TEXT).style(self.theme.help).block(Block::default().title("Help").borders(Borders::ALL).border_set(border::THICK)), help_area);
+        }
     }
 
     fn handle_input(&mut self) -> io::Result<Option<RuntimeState>> {
         if let Ok(true) = event::poll(POLL_TIME) {
-            if let Event::Key(key) = event::read()? {
+            let event = event::read()?;
+            if let Event::Mouse(mouse) = event {
+                return Ok(self.handle_mouse(mouse));
+            }
+            if let Event::Key(key) = event {
+                if key.kind == KeyEventKind::Press {
+                    self.input_error = None;
+                }
+                //The help screen takes all input while it is shown.
+                if self.show_help {
+                    if key.kind == KeyEventKind::Press && key.code == KeyCode::Esc {
+                        self.show_help = false;
+                    }
+                    return Ok(None);
+                }
+                let wants_help = match key.code {
+                    KeyCode::F(1) => true,
+                    KeyCode::Char('?') => matches!(self.ui_mode, UiMode::Normal | UiMode::Command | UiMode::Paused),
+                    _ => false,
+                };
+                if key.kind == KeyEventKind::Press && wants_help {
+                    self.show_help = true;
+                    return Ok(None);
+                }
                 match self.ui_mode {
                     UiMode::Normal => {
-                        if key.kind == KeyEventKind::Press {
-                            match key.code {
-                                KeyCode::Esc => {self.ui_mode = UiMode::Command},
-                                _ => {}
-                            }
+                        if key.kind == KeyEventKind::Press && key.code == KeyCode::Esc {
+                            self.ui_mode = UiMode::Command;
                         }
                     },
                     UiMode::WaitingForInput => {
@@ -183,6 +702,52 @@ impl MainUiState {
                                 KeyCode::Char(letter) => {
                                     self.input_buffer.push(letter);
                                 },
+                                KeyCode::Tab => {
+                                    self.ui_mode = UiMode::WaitingForRawInput;
+                                    self.input_buffer.clear();
+                                },
+                                KeyCode::Up if !self.input_history.is_empty() => {
+                                    let index = match self.history_cursor {
+                                        Some(index) => index.saturating_sub(1),
+                                        None => self.input_history.len() - 1,
+                                    };
+                                    self.history_cursor = Some(index);
+                                    self.input_buffer.clone_from(&self.input_history[index]);
+                                },
+                                KeyCode::Down => {
+                                    match self.history_cursor {
+                                        Some(index) if index + 1 < self.input_history.len() => {
+                                            self.history_cursor = Some(index + 1);
+                                            self.input_buffer.clone_from(&self.input_history[index + 1]);
+                                        },
+                                        _ => {
+                                            //Past the newest entry; back to an empty line.
+                                            self.history_cursor = None;
+                                            self.input_buffer.clear();
+                                        },
+                                    }
+                                },
+                                _ => ()
+                            }
+                        }
+                    },
+                    UiMode::WaitingForRawInput => {
+                        //Input as hexadecimal byte values, separated by spaces.
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_hexdigit() || ch == ' ' => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.trim().is_empty() => {
+                                    self.ui_mode = UiMode::RawInputReady;
+                                },
+                                KeyCode::Tab => {
+                                    self.ui_mode = UiMode::WaitingForInput;
+                                    self.input_buffer.clear();
+                                },
                                 _ => ()
                             }
                         }
@@ -190,26 +755,149 @@ impl MainUiState {
                     UiMode::Command => {
                         if key.kind == KeyEventKind::Press {
                             match key.code {
-                                KeyCode::Char('q') => {self.exit = true;},
+                                KeyCode::Char('q') => {self.ui_mode = UiMode::ConfirmQuit;},
                                 KeyCode::Char('s') => {return Ok(Some(RuntimeState::SingleStep))},
+                                KeyCode::BackTab => {return Ok(Some(RuntimeState::StepOver))},
+                                KeyCode::Char('O') => {return Ok(Some(RuntimeState::StepOut))},
                                 KeyCode::Char('a') => {self.ui_mode = UiMode::WaitingForAddress;
                                     self.input_buffer = String::with_capacity(5)},
                                 KeyCode::Char('n') => {self.ui_mode = UiMode::WaitingForCount;
                                     self.input_buffer = String::with_capacity(6)},
+                                KeyCode::Char('o') => {self.ui_mode = UiMode::WaitingForOutputText;
+                                    self.input_buffer = String::with_capacity(32)},
+                                KeyCode::Char('j') => {self.ui_mode = UiMode::WaitingForInjection;
+                                    self.input_buffer = String::with_capacity(32)},
                                 KeyCode::Char('r') => {return Ok(Some(RuntimeState::Run))},
+                                KeyCode::Char('p') => {
+                                    if self.profiling {
+                                        self.ui_mode = UiMode::WaitingForPath(PathDestination::Profile);
+                                        self.input_buffer = String::with_capacity(32);
+                                    } else {
+                                        self.profiling = true;
+                                        return Ok(Some(RuntimeState::ProfileStart));
+                                    }
+                                },
+                                KeyCode::Char('t') => {
+                                    if self.tracing {
+                                        self.tracing = false;
+                                        return Ok(Some(RuntimeState::TraceStop));
+                                    } else {
+                                        self.ui_mode = UiMode::WaitingForPath(PathDestination::Trace);
+                                        self.input_buffer = String::with_capacity(32);
+                                    }
+                                },
+                                KeyCode::Char('k') if self.tracing => {
+                                    self.ui_mode = UiMode::WaitingForTraceMark;
+                                    self.input_buffer = String::with_capacity(32);
+                                },
+                                KeyCode::Char('b') => {self.ui_mode = UiMode::WaitingForBreakpoint;
+                                    self.input_buffer = String::with_capacity(5)},
+                                KeyCode::Char('B') => {
+                                    self.breakpoints.clear();
+                                    return Ok(Some(RuntimeState::ClearBreakpoints));
+                                },
+                                KeyCode::Char('y') => {self.ui_mode = UiMode::WaitingForOpcode;
+                                    self.input_buffer = String::with_capacity(5)},
+                                KeyCode::Char('Y') => {
+                                    self.opcode_breaks.clear();
+                                    return Ok(Some(RuntimeState::ClearOpcodeBreaks));
+                                },
+                                KeyCode::Char('g') => {
+                                    if self.running {
+                                        self.show_message(String::from("The call stack can only be shown while the VM is paused."));
+                                    } else {
+                                        self.show_message(format!("Call stack: {}",self.backtrace()));
+                                    }
+                                },
+                                KeyCode::Char('z') => {self.ui_mode = UiMode::WaitingForSlot(false);},
+                                KeyCode::Char('Z') => {self.ui_mode = UiMode::WaitingForSlot(true);},
+                                KeyCode::Char('S') => {
+                                    self.ui_mode = UiMode::WaitingForPath(PathDestination::SaveBreakpoints);
+                                    self.input_buffer = String::with_capacity(32);
+                                },
+                                KeyCode::Char('G') => {
+                                    self.ui_mode = UiMode::WaitingForPath(PathDestination::LoadBreakpoints);
+                                    self.input_buffer = String::with_capacity(32);
+                                },
+                                KeyCode::Char('v') => {self.ui_mode = UiMode::WaitingForWatch;
+                                    self.input_buffer = String::with_capacity(16)},
+                                KeyCode::Char('V') => {self.watches.clear();},
+                                KeyCode::Char('h') => {
+                                    //The registers as shown, which are older ones when scrolled back.
+                                    let shown = self.prog_states.len().checked_sub(self.history_offset + 1);
+                                    self.checkpoint = Some(shown.and_then(|index| self.prog_states.get(index))
+                                        .map_or_else(|| DEFAULT_STATE.registers, |step| step.registers.clone()));
+                                    self.compare_to_checkpoint = true;
+                                },
+                                KeyCode::Char('H') if self.checkpoint.is_some() => {
+                                    self.compare_to_checkpoint = !self.compare_to_checkpoint;
+                                },
+                                KeyCode::Char('e') => {return Ok(Some(RuntimeState::Reset))},
+                                KeyCode::Char('i') => {self.echo_input = !self.echo_input;},
+                                KeyCode::Char('c') => {
+                                    self.terminal_text.clear();
+                                    self.styled_terminal.clear();
+                                    self.cleared_output = self.raw_output.chars().count();
+                                    self.output_cursor = OutputCursor::default();
+                                },
+                                KeyCode::Char('w') => {self.ui_mode = UiMode::WaitingForDumpStart;
+                                    self.input_buffer = String::with_capacity(5)},
+                                KeyCode::Char('W') => {
+                                    self.ui_mode = UiMode::WaitingForPath(PathDestination::Binary);
+                                    self.input_buffer = String::with_capacity(32);
+                                },
+                                KeyCode::Char('m') => {self.ui_mode = UiMode::WaitingForPokeAddress;
+                                    self.input_buffer = String::with_capacity(5)},
+                                KeyCode::Char('u') => {return Ok(Some(RuntimeState::UndoEdit))},
+                                KeyCode::Char('f') => {self.ui_mode = UiMode::WaitingForFindAddress;
+                                    self.input_buffer = String::with_capacity(5)},
+                                KeyCode::Up => {
+                                    //Keep at least one step in view.
+                                    self.history_offset = (self.history_offset + 1).min(self.prog_states.len().saturating_sub(1));
+                                },
+                                KeyCode::Down => {self.history_offset = self.history_offset.saturating_sub(1);},
+                                KeyCode::End => {self.history_offset = 0;},
+                                KeyCode::Char('d') => {
+                                    self.ui_mode = UiMode::WaitingForPath(PathDestination::Analysis);
+                                    self.input_buffer = String::with_capacity(32);
+                                },
+                                KeyCode::Char('x') => {
+                                    self.ui_mode = UiMode::WaitingForPath(PathDestination::Transcript);
+                                    self.input_buffer = String::with_capacity(32);
+                                },
+                                KeyCode::Char('l') => {
+                                    if self.logging {
+                                        self.logging = false;
+                                        return Ok(Some(RuntimeState::StopLogOutput));
+                                    } else {
+                                        self.ui_mode = UiMode::WaitingForPath(PathDestination::OutputLog);
+                                        self.input_buffer = String::with_capacity(32);
+                                    }
+                                },
                                 KeyCode::Esc => {self.ui_mode = UiMode::Normal;},
                                 _ => {}//By default, ignore all unknown keypresses.
                             }
                         }
                     }
+                    UiMode::ConfirmQuit => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char('y') | KeyCode::Char('Y') => {self.exit = true;},
+                                KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {self.ui_mode = UiMode::Command;},
+                                _ => ()
+                            }
+                        }
+                    }
                     UiMode::WaitingForAddress => {
                         if key.kind == KeyEventKind::Press {
                             if let KeyCode::Char(ch) = key.code {
-                                if ch.is_digit(16) { //hexadecimal address entry!
+                                if ch.is_ascii_hexdigit() { //hexadecimal address entry!
                                     self.input_buffer.push(ch);
                                 }
+                            } else if let KeyCode::Backspace = key.code {
+                                self.input_buffer.pop();
                             } else if let KeyCode::Enter = key.code {
-                                if self.input_buffer.len() > 0 {
+                                if !self.input_buffer.is_empty() {
                                     self.ui_mode = UiMode::AddressReady;
                                 } else {
                                     self.ui_mode = UiMode::Normal;
@@ -217,14 +905,254 @@ impl MainUiState {
                             }
                         }
                     }
+                    UiMode::WaitingForDumpStart | UiMode::WaitingForDumpEnd(_) => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_hexdigit() && self.input_buffer.len() < 4 => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    //Only four hexadecimal digits fit, so this always parses.
+                                    let address = u16::from_str_radix(&self.input_buffer[..], 16).unwrap_or(0);
+                                    self.input_buffer.clear();
+                                    self.ui_mode = match self.ui_mode {
+                                        UiMode::WaitingForDumpEnd(start) if start > address => {
+                                            self.show_message(format!("[UI error] The dump can't end at {address:04x}, before it starts at {start:04x}"));
+                                            UiMode::Normal
+                                        },
+                                        UiMode::WaitingForDumpEnd(start) => UiMode::WaitingForPath(PathDestination::MemoryDump(start, address)),
+                                        _ => UiMode::WaitingForDumpEnd(address),
+                                    };
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Normal;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForPokeAddress | UiMode::WaitingForPokeValue(_) => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_hexdigit() && self.input_buffer.len() < 4 => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    if let UiMode::WaitingForPokeValue(address) = self.ui_mode {
+                                        self.ui_mode = UiMode::PokeReady(address);
+                                    } else {
+                                        //Only four hexadecimal digits fit, so this always parses.
+                                        let address = u16::from_str_radix(&self.input_buffer[..], 16).unwrap_or(0);
+                                        self.input_buffer.clear();
+                                        self.ui_mode = UiMode::WaitingForPokeValue(address);
+                                    }
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Normal;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForOpcode => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_alphabetic() && self.input_buffer.len() < 4 => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    let Some(opcode) = opcode_by_name(&self.input_buffer) else {
+                                        self.input_error = Some(format!("No instruction is called '{}'",self.input_buffer));
+                                        return Ok(None);
+                                    };
+                                    self.ui_mode = UiMode::Command;
+                                    //Entering one that is already there takes it away again.
+                                    if self.opcode_breaks.remove(&opcode) {
+                                        return Ok(Some(RuntimeState::RemoveOpcodeBreak(opcode)));
+                                    }
+                                    self.opcode_breaks.insert(opcode);
+                                    return Ok(Some(RuntimeState::BreakOnOpcode(opcode)));
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForSlot(load) => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_digit() => {
+                                    self.ui_mode = UiMode::Command;
+                                    //A single digit always fits in a u8.
+                                    let slot = ch as u8 - b'0';
+                                    if load {
+                                        return Ok(Some(RuntimeState::QuickLoad(slot)));
+                                    }
+                                    self.show_message(format!("Saved to slot {slot}"));
+                                    return Ok(Some(RuntimeState::QuickSave(slot)));
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForWatch => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if !ch.is_control() => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    match WatchExpression::parse(&self.input_buffer) {
+                                        Ok(watch) => {
+                                            self.watches.push(watch);
+                                            self.ui_mode = UiMode::Command;
+                                        },
+                                        //Leave the expression as it is, so the mistake can be fixed.
+                                        Err(e) => self.input_error = Some(format!("Can't watch '{}': {e}",self.input_buffer)),
+                                    }
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForInjection => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if !ch.is_control() => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    match assemble(&self.input_buffer) {
+                                        Ok(words) => {
+                                            self.ui_mode = UiMode::Command;
+                                            return Ok(Some(RuntimeState::Inject(words)));
+                                        },
+                                        //Leave the line as it is, so the mistake can be fixed.
+                                        Err(e) => self.input_error = Some(format!("Can't assemble '{}': {e}",self.input_buffer)),
+                                    }
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForTraceMark | UiMode::WaitingForOutputText => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if !ch.is_control() => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    let text = std::mem::take(&mut self.input_buffer);
+                                    let state = if self.ui_mode == UiMode::WaitingForTraceMark {
+                                        RuntimeState::TraceMark(text)
+                                    } else {
+                                        RuntimeState::RunUntilOutputContains(text)
+                                    };
+                                    self.ui_mode = UiMode::Normal;
+                                    return Ok(Some(state));
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Normal;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForBreakpoint => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_hexdigit() && self.input_buffer.len() < 4 => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    let address = u16::from_str_radix(&self.input_buffer[..], 16).unwrap_or(0);
+                                    self.ui_mode = UiMode::Command;
+                                    //Entering a breakpoint that is already there takes it away again.
+                                    if self.breakpoints.remove(&address) {
+                                        return Ok(Some(RuntimeState::RemoveBreakpoint(address)));
+                                    }
+                                    self.breakpoints.insert(address);
+                                    return Ok(Some(RuntimeState::AddBreakpoint(address)));
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    UiMode::WaitingForFindAddress => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if ch.is_ascii_hexdigit() && self.input_buffer.len() < 4 => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    let address = u16::from_str_radix(&self.input_buffer[..], 16).unwrap_or(0);
+                                    self.find_in_history(address);
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Command;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
                     UiMode::WaitingForCount => {
                         if key.kind == KeyEventKind::Press {
                             if let KeyCode::Char(ch) = key.code {
-                                if ch.is_digit(10) {
+                                if ch.is_digit(self.count_radix()) {
                                     self.input_buffer.push(ch);
                                 }
+                            } else if let KeyCode::Backspace = key.code {
+                                self.input_buffer.pop();
+                            } else if let KeyCode::Tab = key.code {
+                                //Switch base, keeping the number typed so far.
+                                let count = usize::from_str_radix(&self.input_buffer[..], self.count_radix()).ok();
+                                self.count_in_hex = !self.count_in_hex;
+                                self.input_buffer = match count {
+                                    Some(count) if self.count_in_hex => format!("{count:x}"),
+                                    Some(count) => count.to_string(),
+                                    None => String::new(),
+                                };
                             } else if let KeyCode::Enter = key.code {
-                                if self.input_buffer.len() > 0 {
+                                if !self.input_buffer.is_empty() {
                                     self.ui_mode = UiMode::CountReady;
                                 } else {
                                     self.ui_mode = UiMode::Normal;
@@ -232,9 +1160,31 @@ impl MainUiState {
                             }
                         }
                     }
+                    UiMode::WaitingForPath(destination) => {
+                        if key.kind == KeyEventKind::Press {
+                            match key.code {
+                                KeyCode::Char(ch) if !ch.is_control() => {
+                                    self.input_buffer.push(ch);
+                                },
+                                KeyCode::Backspace => {
+                                    self.input_buffer.pop();
+                                },
+                                KeyCode::Enter if !self.input_buffer.is_empty() => {
+                                    self.ui_mode = UiMode::PathReady(destination);
+                                },
+                                KeyCode::Esc => {
+                                    self.ui_mode = UiMode::Normal;
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
                     UiMode::InputReady | 
+                    UiMode::RawInputReady |
                     UiMode::AddressReady |
                     UiMode::CountReady |
+                    UiMode::PokeReady(_) |
+                    UiMode::PathReady(_) |
                     UiMode::Paused => {
                         if key.kind == KeyEventKind::Press && key.code == KeyCode::Esc {
                             self.ui_mode = UiMode::Command;
@@ -249,43 +1199,311 @@ impl MainUiState {
     }
 
 
+    /// Clicking an instruction in the history runs until that address is reached again,
+    /// much like a one-time breakpoint.
+    fn handle_mouse(&mut self, mouse:MouseEvent) -> Option<RuntimeState> {
+        if mouse.kind != MouseEventKind::Down(MouseButton::Left) || self.show_help {
+            return None;
+        }
+        if !matches!(self.ui_mode, UiMode::Normal | UiMode::Command | UiMode::Paused) {
+            return None;
+        }
+        //Only the inside of the pane, not its borders.
+        let area = self.history_area;
+        let inside_columns = area.x < mouse.column && mouse.column + 1 < area.x + area.width;
+        let inside_rows = area.y < mouse.row && mouse.row + 1 < area.y + area.height;
+        if !inside_columns || !inside_rows {
+            return None;
+        }
+        //The pane shows the most recent steps (up to where the history is scrolled to),
+        // oldest at the top.
+        let shown_steps = self.prog_states.len() - self.history_offset;
+        let visible = shown_steps.min(area.height.saturating_sub(2) as usize);
+        let row = (mouse.row - area.y - 1) as usize;
+        let index = (shown_steps - visible) + row;
+        if index >= shown_steps {
+            return None;
+        }
+        self.prog_states.get(index)
+            .map(|step| RuntimeState::RunUntilAddress(step.registers.program_counter))
+    }
+
+    /// Scroll the history to the most recent step at `address`, if there is one.
+    fn find_in_history(&mut self, address:u16) {
+        let found = self.prog_states.iter()
+            .rposition(|step| step.registers.program_counter == address);
+        match found {
+            Some(index) => self.history_offset = self.prog_states.len() - 1 - index,
+            None => self.show_message(format!("[UI] Address {address:04x} is not in the history")),
+        }
+    }
+
+    /// The instruction the VM is about to execute, with what its register operands hold.
+    /// Only known while the VM is paused; a word that isn't an instruction is shown as is.
+    fn next_instruction_line(&self) -> Line<'static> {
+        let view = &self.memory_view;
+        if self.running || self.halted || view.words.is_empty() {
+            return Line::raw("");
+        }
+        let operation = Operation::from(view.words[0]);
+        let operands = view.words.get(1..1 + operation.operands() as usize);
+        let text = match operands {
+            Some(operands) if !matches!(operation, Operation::Error(_)) &&
+                operands.iter().all(|op| !matches!(ParsedValue::from(*op), ParsedValue::Error(_))) => {
+                let mut text = format!("{operation}");
+                for op in operands {
+                    match ParsedValue::from(*op) {
+                        ParsedValue::Register(index) => text.push_str(&format!(" {:#}={:04x}",ParsedValue::Register(index),view.registers[index as usize])),
+                        value => text.push_str(&format!(" {value}")),
+                    }
+                }
+                text
+            },
+            _ => format!("<{:04x}>, not an instruction",view.words[0]),
+        };
+        Line::from(vec![
+            Span::styled(format!("next {:04x}: ",view.start), self.theme.text),
+            Span::styled(text, self.theme.input),
+        ])
+    }
+
+    /// Show an error reported by the VM on a line of its own in the main output window.
+    fn show_error(&mut self, message:String) {
+        self.show_message(format!("[VM error] {message}"));
+    }
+
+    /// Name of the subroutine `address` is in: the nearest one starting at or before it, or
+    /// `main` if there is none.
+    fn function_name(&self, address:u16) -> String {
+        match self.functions.partition_point(|start| *start <= address) {
+            0 => String::from("main"),
+            index => format!("fn_{:04x}",self.functions[index - 1]),
+        }
+    }
+
+    /// The calls that led to the current instruction, innermost first, like
+    /// `fn_17a1 <- fn_1820 <- main`.
+    fn backtrace(&self) -> String {
+        std::iter::once(self.memory_view.start)
+            .chain(self.memory_view.call_stack.iter().copied())
+            .map(|address| self.function_name(address))
+            .collect::<Vec<_>>()
+            .join(" <- ")
+    }
+
+    /// Put a line in the terminal pane, apart from the output around it.
+    fn show_message(&mut self, message:String) {
+        if self.terminal_text.last().is_some_and(|line| !line.is_empty()) {
+            self.terminal_text.push(String::with_capacity(50));
+        }
+        self.terminal_text.push(message);
+        self.terminal_text.push(String::with_capacity(50));
+        self.output_cursor.column = 0;
+    }
+
     ///
     /// Write a new string to the main output window.
     /// If the string contains one or more line-breaks (0x0A), new lines will be generated.
+    /// A carriage return (0x0D) goes back to the start of the line, to write over it.
     fn prep_string_input(&mut self, src:String) {
-        if src.len() == 0 {
+        if src.is_empty() {
             return
         }
-        if self.terminal_text.len() == 0 {
+        if self.terminal_text.is_empty() {
             self.terminal_text.push(String::with_capacity(50));
+            self.output_cursor.column = 0;
         }
-        let mut top_line = self.terminal_text.last_mut().expect("Should be impossible, just pushed a blank string.");
-        
         for cr in src.chars() {
-            match cr {
-                '\u{000A}' => {
-                    self.terminal_text.push(String::with_capacity(50));
-                    top_line = self.terminal_text.last_mut().expect("should be impossible, just pushed a new string.");
-                },
-                any => {
-                    top_line.push(any);
-                    if top_line.len() >= TERMINAL_WIDTH {
-                        self.terminal_text.push(String::with_capacity(50));
-                        top_line = self.terminal_text.last_mut().expect("This should be unreachable.");
-                    }
+            self.output_cursor.put(&mut self.terminal_text, cr, self.terminal_width, self.ansi_colors);
+        }
+    }
+}
+
+/// Where the next character of output goes on the last line of the terminal pane.
+#[derive(Debug,Default)]
+struct OutputCursor {
+    /// Only short of the end of the line after a carriage return.
+    column:usize,
+    /// An escape sequence that hasn't been received in full yet.
+    escape:String,
+}
+
+impl OutputCursor {
+    /// Add one character of output to `lines`. A carriage return only moves the column back
+    /// to the start, so what follows replaces the line character by character, the way a
+    /// terminal redraws a status line. With `ansi`, escape sequences are kept whole in the
+    /// text at the cursor, without taking up a column, to be turned into styles when shown.
+    fn put(&mut self, lines:&mut Vec<String>, cr:char, width:usize, ansi:bool) {
+        let top_line = lines.last_mut().expect("Never empty, starts with a line.");
+        let position = |line:&str, column:usize| if ansi {visible_position(line, column)} else {line.char_indices().nth(column)};
+        if ansi && (cr == ESCAPE || !self.escape.is_empty()) {
+            self.escape.push(cr);
+            if sequence_complete(&self.escape) {
+                let index = position(top_line, self.column).map_or(top_line.len(), |(index,_)| index);
+                top_line.insert_str(index, &self.escape);
+                self.escape.clear();
+            }
+            return;
+        }
+        match cr {
+            '\u{000A}' => {
+                lines.push(String::with_capacity(50));
+                self.column = 0;
+            },
+            '\u{000D}' => self.column = 0,
+            any => {
+                match position(top_line, self.column) {
+                    Some((index,old)) => top_line.replace_range(index..index + old.len_utf8(), any.encode_utf8(&mut [0;4])),
+                    None => top_line.push(any),
+                }
+                self.column += 1;
+                if self.column >= width {
+                    lines.push(String::with_capacity(50));
+                    self.column = 0;
                 }
             }
         }
     }
 }
 
-impl Widget for &RegisterState {
+/// Read breakpoints saved with `S`: one hexadecimal address per line. Empty lines and
+/// comment lines are skipped.
+fn load_breakpoints(path:&str) -> Result<BTreeSet<u16>,String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut addresses = BTreeSet::new();
+    for (number,line) in config_lines(&contents) {
+        let address = u16::from_str_radix(line, 16)
+            .map_err(|e| format!("{path}:{number}: '{line}' is not a hexadecimal address: {e}"))?;
+        addresses.insert(address);
+    }
+    Ok(addresses)
+}
+
+/// Split program output into lines the way the terminal pane does.
+fn output_lines(text:&str, width:usize, ansi:bool) -> Vec<String> {
+    let mut lines = vec![String::with_capacity(50)];
+    let mut cursor = OutputCursor::default();
+    for cr in text.chars() {
+        cursor.put(&mut lines, cr, width, ansi);
+    }
+    lines
+}
+
+/// As much of the end of `text` as fits in `width` characters, so whatever is being typed
+/// stays in view. A cut-off start is marked with an ellipsis.
+fn input_tail(text:&str, width:usize) -> String {
+    let length = text.chars().count();
+    if length <= width {
+        return String::from(text);
+    }
+    let kept = width.saturating_sub(1);
+    let mut tail = String::from("…");
+    tail.extend(text.chars().skip(length - kept));
+    tail
+}
+
+/// A rectangle of at most `width` by `height` in the middle of `area`.
+fn centered_rect(area:Rect, width:u16, height:u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
+
+/// Decode up to `count` instructions from a view of memory. Once a word turns up that is
+/// not a valid instruction (or has invalid operands), that word and everything after it
+/// are shown as raw values, since any decoding past that point is guesswork.
+fn disassemble_view(view:&MemoryView, count:usize) -> Vec<Line<'static>> {
+    let mut lines = Vec::with_capacity(count);
+    let mut offset = 0;
+    let mut in_data = false;
+    while lines.len() < count && offset < view.words.len() {
+        let address = view.start.wrapping_add(offset as u16);
+        let word = view.words[offset];
+        let operation = Operation::from(word);
+        let operand_count = operation.operands() as usize;
+        let operands = view.words.get(offset+1..offset+1+operand_count);
+        let valid = !matches!(operation, Operation::Error(_)) &&
+            operands.is_some_and(|ops| ops.iter().all(|op| !matches!(ParsedValue::from(*op), ParsedValue::Error(_))));
+        in_data |= !valid;
+        if in_data {
+            lines.push(Line::from(format!("{address:04x}: <{word:04x}>")));
+            offset += 1;
+        } else {
+            let mut text = format!("{address:04x}:{operation}");
+            for op in operands.unwrap_or_default() {
+                text.push_str(&format!(" {}",ParsedValue::from(*op)));
+            }
+            lines.push(Line::from(text));
+            offset += 1 + operand_count;
+        }
+    }
+    lines
+}
+
+/// The register display. Registers that differ from the previous step are highlighted.
+struct RegisterPane<'a> {
+    current:&'a RegisterState,
+    previous:Option<&'a RegisterState>,
+    /// When given, registers are compared to this instead, showing how much they changed
+    /// and dimming the ones that didn't.
+    checkpoint:Option<&'a RegisterState>,
+}
+
+/// A value followed by how much it went up or down, if it changed.
+fn with_change(from:usize, to:usize, hex:bool) -> String {
+    let (sign,difference) = if to >= from {('+', to - from)} else {('-', from - to)};
+    match (difference,hex) {
+        (0,true) => format!("{to:04x}"),
+        (0,false) => format!("{to}"),
+        (_,true) => format!("{to:04x} {sign}{difference:x}"),
+        (_,false) => format!("{to} {sign}{difference}"),
+    }
+}
+
+impl Widget for RegisterPane<'_> {
     fn render(self, area: Rect, buf: &mut Buffer)
     where
         Self: Sized {
+        if let Some(checkpoint) = self.checkpoint {
+            let compared = |name:String, from:usize, to:usize, hex:bool, width:usize| -> Span<'static> {
+                let text = format!("{name}:{:<width$}",with_change(from, to, hex));
+                if from == to {text.dim()} else {text.reversed()}
+            };
+            let line = |indices:[usize;4], extra:Span<'static>| {
+                let mut spans:Vec<Span> = Vec::new();
+                for index in indices {
+                    let from = checkpoint.registers[index] as usize;
+                    spans.push(compared(format!("R{index}"), from, self.current.registers[index] as usize, true, 10));
+                    spans.push(" ".into());
+                }
+                spans.push(extra);
+                Line::from(spans)
+            };
+            let text = vec![
+                line([0,1,2,3], compared(String::from(" PC"), checkpoint.program_counter as usize, self.current.program_counter as usize, false, 0)),
+                line([4,5,6,7], compared(String::from(" ST"), checkpoint.stack_depth, self.current.stack_depth, false, 0)),
+            ];
+            let par = Paragraph::new(text).block(Block::default().title("registers, compared to the checkpoint").borders(Borders::ALL).border_set(border::THICK));
+            return par.render(area,buf);
+        }
+        let register_span = |index:usize| {
+            let value = self.current.registers[index];
+            let text = format!("R{index}:{value:04x}");
+            if self.previous.is_some_and(|previous| previous.registers[index] != value) {
+                text.reversed()
+            } else {
+                text.into()
+            }
+        };
         let text = vec![
-            format!("R0:{:04x} R1:{:04x} R2:{:04x} R3:{:04x}  PC:{}",self.registers[0],self.registers[1],self.registers[2],self.registers[3],self.program_counter).into(),
-            format!("R4:{:04x} R5:{:04x} R6:{:04x} R7:{:04x}  ST:{}",self.registers[4],self.registers[5],self.registers[6],self.registers[7],self.stack_depth).into()
+            Line::from(vec![
+                register_span(0)," ".into(),register_span(1)," ".into(),register_span(2)," ".into(),register_span(3),
+                format!("  PC:{}",self.current.program_counter).into()
+            ]),
+            Line::from(vec![
+                register_span(4)," ".into(),register_span(5)," ".into(),register_span(6)," ".into(),register_span(7),
+                format!("  ST:{}",self.current.stack_depth).into()
+            ])
         ];
         let par = Paragraph::new(text).block(Block::default().title("registers").borders(Borders::ALL).border_set(border::THICK));
         par.render(area,buf)
@@ -297,6 +1515,15 @@ impl Widget for &MainUiState {
     where
         Self: Sized {
             //Set up the layout.
+            let title_key = Style::new().fg(self.theme.hotkey.fg.unwrap_or(Color::Blue)).bold();
+            let state_title = if self.queued_input > 0 {
+                format!(" {} ({} chars of input queued) ",self.run_state_name(),self.queued_input)
+            } else {
+                format!(" {} ",self.run_state_name())
+            };
+            let rate_title = format!("{:.0} instructions/s",self.instructions_per_second());
+            //Room for a prompt and what is typed after it, between the corners and the titles on the right.
+            let input_width = (area.width as usize).saturating_sub(4 + state_title.len() + rate_title.len());
             let block_title:Title;
             let mut block_content:Line = Line::raw("");
             match self.ui_mode {
@@ -304,24 +1531,79 @@ impl Widget for &MainUiState {
                     //Show instructions.
                     block_title = Title::from(Line::from(vec![
                         "press ".into(),
-                        "esc".bold().blue(),
-                        " to enter Command Mode".into()
+                        Span::styled("esc", title_key),
+                        " to enter Command Mode, ".into(),
+                        Span::styled("?", title_key),
+                        " for help".into()
                     ]));
+                    block_content = self.next_instruction_line();
                 },
                 UiMode::WaitingForInput |
-                UiMode::WaitingForCount |
-                UiMode::WaitingForAddress=> {
+                UiMode::WaitingForAddress |
+                UiMode::WaitingForPath(_) => {
                     //Show input field.
-                    let buff = &self.input_buffer[..];
+                    let buff = input_tail(&self.input_buffer, input_width.saturating_sub(3));
                     block_title = Title::from(Line::from(vec![
                         "> ".into(),
-                        buff.green(),
-                        "█".white()
+                        Span::styled(buff, self.theme.input),
+                        Span::styled("█", self.theme.text)
+                    ]));
+                },
+                UiMode::WaitingForCount |
+                UiMode::WaitingForFindAddress |
+                UiMode::WaitingForTraceMark |
+                UiMode::WaitingForOutputText |
+                UiMode::WaitingForInjection |
+                UiMode::WaitingForWatch |
+                UiMode::WaitingForOpcode |
+                UiMode::WaitingForSlot(_) |
+                UiMode::WaitingForBreakpoint |
+                UiMode::WaitingForDumpStart |
+                UiMode::WaitingForDumpEnd(_) |
+                UiMode::WaitingForPokeAddress |
+                UiMode::WaitingForPokeValue(_) => {
+                    let prompt = match self.ui_mode {
+                        UiMode::WaitingForDumpStart => String::from("dump from address> "),
+                        UiMode::WaitingForDumpEnd(start) => format!("dump from {start:04x} up to and including> "),
+                        UiMode::WaitingForFindAddress => String::from("find address> "),
+                        UiMode::WaitingForTraceMark => String::from("note for the trace> "),
+                        UiMode::WaitingForOutputText => String::from("run until output> "),
+                        UiMode::WaitingForInjection => String::from("instruction to execute> "),
+                        UiMode::WaitingForWatch => String::from("value to watch> "),
+                        UiMode::WaitingForOpcode => String::from("toggle pausing after instruction> "),
+                        UiMode::WaitingForSlot(false) => String::from("quick save to slot (0-9)> "),
+                        UiMode::WaitingForSlot(true) => String::from("quick load from slot (0-9)> "),
+                        UiMode::WaitingForBreakpoint => String::from("toggle breakpoint at> "),
+                        UiMode::WaitingForCount => String::from(if self.count_in_hex {"steps (hex)> "} else {"steps (dec)> "}),
+                        UiMode::WaitingForPokeValue(address) => format!("value for {address:04x}> "),
+                        _ => String::from("address to change> "),
+                    };
+                    let buff = input_tail(&self.input_buffer, input_width.saturating_sub(prompt.len() + 1));
+                    block_title = Title::from(Line::from(vec![
+                        prompt.into(),
+                        Span::styled(buff, self.theme.input),
+                        Span::styled("█", self.theme.text)
                     ]));
                 },
+                UiMode::WaitingForRawInput => {
+                    let buff = input_tail(&self.input_buffer, input_width.saturating_sub(6));
+                    block_title = Title::from(Line::from(vec![
+                        "raw> ".into(),
+                        Span::styled(buff, self.theme.input),
+                        Span::styled("█", self.theme.text)
+                    ]));
+                    block_content = Line::from(vec![
+                        Span::styled("Hexadecimal bytes, separated by spaces|", self.theme.text),
+                        Span::styled("tab", self.theme.hotkey),
+                        Span::styled(" back to text input", self.theme.text)
+                    ]);
+                },
                 UiMode::InputReady |
+                UiMode::RawInputReady |
+                UiMode::PokeReady(_) |
                 UiMode::CountReady |
-                UiMode::AddressReady => {
+                UiMode::AddressReady |
+                UiMode::PathReady(_) => {
                     //Show 'please stand by' message, until input is sent.
                     block_title = Title::from("Sending input, stand by.");
                 }
@@ -329,33 +1611,139 @@ impl Widget for &MainUiState {
                     //Show command options.
                     block_title = Title::from("Command mode");
                     block_content = Line::from(vec![
-                        "(".white(),
-                        "esc".blue().on_white(),
-                        ") exit command mode|".white(),
-                        "R".blue().on_white(),
-                        "un in normal mode,".white(),
-                        "S".blue().on_white(),
-                        "ingle step|".white(),
-                        "Run until ".white(),
-                        "a".blue().on_white(),
-                        "ddress|".white(),
-                        "Run for ".white(),
-                        "N".blue().on_white(),
-                        " steps|".white(),
-                        "Q".blue().on_white(),
-                        "uit".white()
+                        Span::styled("(", self.theme.text),
+                        Span::styled("esc", self.theme.hotkey),
+                        Span::styled(") exit command mode|", self.theme.text),
+                        Span::styled("R", self.theme.hotkey),
+                        Span::styled("un in normal mode,", self.theme.text),
+                        Span::styled("Single step (", self.theme.text),
+                        Span::styled("s", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("⇧tab", self.theme.hotkey),
+                        Span::styled(" step over|", self.theme.text),
+                        Span::styled("Step ", self.theme.text),
+                        Span::styled("O", self.theme.hotkey),
+                        Span::styled("ut|", self.theme.text),
+                        Span::styled("Run until ", self.theme.text),
+                        Span::styled("a", self.theme.hotkey),
+                        Span::styled("ddress|", self.theme.text),
+                        Span::styled("Run for ", self.theme.text),
+                        Span::styled("N", self.theme.hotkey),
+                        Span::styled(" steps|", self.theme.text),
+                        Span::styled("Run until ", self.theme.text),
+                        Span::styled("o", self.theme.hotkey),
+                        Span::styled("utput|", self.theme.text),
+                        Span::styled("B", self.theme.hotkey),
+                        Span::styled("reakpoint|", self.theme.text),
+                        Span::styled("Break on t", self.theme.text),
+                        Span::styled("y", self.theme.hotkey),
+                        Span::styled("pe of instruction|", self.theme.text),
+                        Span::styled("Call stack (", self.theme.text),
+                        Span::styled("g", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("Quick save (", self.theme.text),
+                        Span::styled("z", self.theme.hotkey),
+                        Span::styled(")/load (", self.theme.text),
+                        Span::styled("Z", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("Save breakpoints (", self.theme.text),
+                        Span::styled("S", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("Get breakpoints (", self.theme.text),
+                        Span::styled("G", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("P", self.theme.hotkey),
+                        Span::styled(if self.profiling {"rofile stop|"} else {"rofile start|"}, self.theme.text),
+                        Span::styled("T", self.theme.hotkey),
+                        Span::styled(if self.tracing {"race stop|"} else {"race start|"}, self.theme.text),
+                        Span::styled("Mar", self.theme.text),
+                        Span::styled("k", self.theme.hotkey),
+                        Span::styled(" trace|", self.theme.text),
+                        Span::styled("L", self.theme.hotkey),
+                        Span::styled(if self.logging {"og output stop|"} else {"og output to file|"}, self.theme.text),
+                        Span::styled("R", self.theme.text),
+                        Span::styled("e", self.theme.hotkey),
+                        Span::styled("set|", self.theme.text),
+                        Span::styled("I", self.theme.hotkey),
+                        Span::styled(if self.echo_input {"nput echo off|"} else {"nput echo on|"}, self.theme.text),
+                        Span::styled("Clear terminal (", self.theme.text),
+                        Span::styled("c", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("M", self.theme.hotkey),
+                        Span::styled("odify memory|", self.theme.text),
+                        Span::styled("U", self.theme.hotkey),
+                        Span::styled("ndo memory change|", self.theme.text),
+                        Span::styled("In", self.theme.text),
+                        Span::styled("j", self.theme.hotkey),
+                        Span::styled("ect instruction|", self.theme.text),
+                        Span::styled("V", self.theme.hotkey),
+                        Span::styled("alue to watch|", self.theme.text),
+                        Span::styled("C", self.theme.text),
+                        Span::styled("h", self.theme.hotkey),
+                        Span::styled("eckpoint|", self.theme.text),
+                        Span::styled("↑↓", self.theme.hotkey),
+                        Span::styled(" history|", self.theme.text),
+                        Span::styled("F", self.theme.hotkey),
+                        Span::styled("ind address in history|", self.theme.text),
+                        Span::styled("D", self.theme.hotkey),
+                        Span::styled("isassemble memory|", self.theme.text),
+                        Span::styled("Write memory range (", self.theme.text),
+                        Span::styled("w", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("Save as binary (", self.theme.text),
+                        Span::styled("W", self.theme.hotkey),
+                        Span::styled(")|", self.theme.text),
+                        Span::styled("Ex", self.theme.text),
+                        Span::styled("x", self.theme.hotkey),
+                        Span::styled("port transcript|", self.theme.text),
+                        Span::styled("Q", self.theme.hotkey),
+                        Span::styled("uit", self.theme.text)
                     ]);
                 }
+                UiMode::ConfirmQuit => {
+                    block_title = Title::from("Quit?");
+                },
                 UiMode::Paused => {
-                    block_title = Title::from("Execution paused");
+                    block_title = Title::from(if self.halted {"Program halted"} else {"Execution paused"});
+                    block_content = self.next_instruction_line();
                 },
 
             }
+            if let Some(error) = &self.input_error {
+                block_content = Line::styled(error.as_str(), self.theme.error);
+            }
             Paragraph::new(block_content)
+                .wrap(Wrap { trim: true })
                 .block(Block::default()
                     .title(block_title)
+                    .title(Title::from(Span::styled(state_title, self.theme.hotkey)).alignment(Alignment::Right))
+                    .title(Title::from(rate_title).alignment(Alignment::Right))
                     .borders(Borders::ALL)
                     .border_set(border::THICK))
             .render(area, buf);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_interface::make_interfaces;
+
+    #[test]
+    fn commands_to_a_stopped_vm_are_reported() {
+        let (mut ui_interface, vm_interface) = make_interfaces(false);
+        drop(vm_interface);
+        let mut ui = MainUiState::new(16);
+        ui.send_state(&mut ui_interface, RuntimeState::RunForSteps(5));
+        assert!(ui.terminal_text.iter().any(|line| line.contains("The VM is no longer running")));
+    }
+
+    #[test]
+    fn carriage_return_overwrites_line() {
+        let mut ui = MainUiState::new(16);
+        ui.prep_string_input(String::from("abc\rX"));
+        assert_eq!(ui.terminal_text, ["Xbc"]);
+        ui.prep_string_input(String::from("\r\n"));
+        assert_eq!(ui.terminal_text, ["Xbc", ""]);
+    }
+}
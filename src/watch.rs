@@ -0,0 +1,175 @@
+use std::fmt::{Display,Formatter,Result as fmtResult};
+
+use crate::interface::RegisterState;
+
+/// Arithmetic wraps around at this value, the same as in the VM.
+const MODULUS:u32 = 0x8000;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Term {
+    Literal(u16),
+    Register(usize),
+}
+
+/// A value worked out from the registers, like `R0 + R1` or `R7 & 7fff`. Literals are
+/// hexadecimal and operators are applied from left to right, without precedence.
+#[derive(Debug,Clone,PartialEq)]
+pub struct WatchExpression {
+    text:String,
+    first:Term,
+    rest:Vec<(Operator,Term)>,
+}
+
+impl WatchExpression {
+    pub fn parse(text:&str) -> Result<Self,String> {
+        let tokens = tokenize(text)?;
+        let mut tokens = tokens.into_iter();
+        let first = match tokens.next() {
+            Some(Token::Term(term)) => term,
+            Some(Token::Operator(_)) => return Err(String::from("expected a register or number first")),
+            None => return Err(String::from("nothing to watch")),
+        };
+        let mut rest = Vec::new();
+        while let Some(token) = tokens.next() {
+            let Token::Operator(operator) = token else {
+                return Err(String::from("expected an operator between two values"));
+            };
+            match tokens.next() {
+                Some(Token::Term(term)) => rest.push((operator,term)),
+                _ => return Err(String::from("expected a register or number after the operator")),
+            }
+        }
+        Ok(Self { text: text.split_whitespace().collect::<Vec<_>>().join(" "), first, rest })
+    }
+
+    pub fn evaluate(&self, registers:&RegisterState) -> u16 {
+        let value_of = |term:&Term| match term {
+            Term::Literal(value) => u32::from(*value),
+            Term::Register(index) => u32::from(registers.registers[*index]),
+        };
+        let result = self.rest.iter().fold(value_of(&self.first), |left,(operator,term)| {
+            let right = value_of(term);
+            match operator {
+                Operator::Add => (left + right) % MODULUS,
+                Operator::Subtract => (left + MODULUS - right % MODULUS) % MODULUS,
+                Operator::And => left & right,
+                Operator::Or => left | right,
+                Operator::Xor => left ^ right,
+            }
+        });
+        //Every step stays below 0x10000: literals fit in 16 bits and sums are wrapped.
+        result as u16
+    }
+}
+
+impl Display for WatchExpression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmtResult {
+        write!(f,"{}",self.text)
+    }
+}
+
+enum Token {
+    Term(Term),
+    Operator(Operator),
+}
+
+fn tokenize(text:&str) -> Result<Vec<Token>,String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        let operator = match ch {
+            '+' => Some(Operator::Add),
+            '-' => Some(Operator::Subtract),
+            '&' => Some(Operator::And),
+            '|' => Some(Operator::Or),
+            '^' => Some(Operator::Xor),
+            _ => None,
+        };
+        if ch.is_whitespace() {
+            chars.next();
+        } else if let Some(operator) = operator {
+            chars.next();
+            tokens.push(Token::Operator(operator));
+        } else if ch.is_ascii_alphanumeric() {
+            let mut word = String::new();
+            while let Some(&next) = chars.peek().filter(|next| next.is_ascii_alphanumeric()) {
+                word.push(next);
+                chars.next();
+            }
+            tokens.push(Token::Term(parse_term(&word)?));
+        } else {
+            return Err(format!("unexpected '{ch}'"));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_term(word:&str) -> Result<Term,String> {
+    let register = word.strip_prefix(['R','r'])
+        .and_then(|index| index.parse::<usize>().ok())
+        .filter(|index| *index < 8);
+    if let Some(index) = register {
+        return Ok(Term::Register(index));
+    }
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u16::from_str_radix(digits, 16)
+        .map(Term::Literal)
+        .map_err(|_| format!("'{word}' is neither a register R0 to R7 nor a hexadecimal number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(text:&str) -> u16 {
+        let registers = RegisterState { registers: [1,2,3,4,5,6,7,0x7fff], ..Default::default() };
+        WatchExpression::parse(text).unwrap().evaluate(&registers)
+    }
+
+    #[test]
+    fn operators_apply_left_to_right() {
+        assert_eq!(evaluate("1 + 2 & 2"), 2);
+        assert_eq!(evaluate("R0 | R1 ^ R2"), 0);
+        assert_eq!(evaluate("r6+R7"), 6);
+    }
+
+    #[test]
+    fn hexadecimal_literals() {
+        assert_eq!(evaluate("0x10"), 0x10);
+        assert_eq!(evaluate("10"), 0x10);
+        assert_eq!(evaluate("R7 & ff"), 0xff);
+    }
+
+    #[test]
+    fn subtraction_wraps_around() {
+        assert_eq!(evaluate("R0 - R1"), 0x7fff);
+        assert_eq!(evaluate("0 - 8000"), 0);
+        assert_eq!(evaluate("R7 + 1"), 0);
+    }
+
+    #[test]
+    fn only_registers_r0_to_r7() {
+        assert!(WatchExpression::parse("R8").is_err());
+        assert!(WatchExpression::parse("R0 + R12").is_err());
+        assert_eq!(WatchExpression::parse("R7").unwrap().first, Term::Register(7));
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        assert!(WatchExpression::parse("").is_err());
+        assert!(WatchExpression::parse("+ R0").is_err());
+        assert!(WatchExpression::parse("R0 R1").is_err());
+        assert!(WatchExpression::parse("R0 +").is_err());
+        assert!(WatchExpression::parse("R0 * 2").is_err());
+        assert_eq!(WatchExpression::parse("  R0   +  1 ").unwrap().to_string(), "R0 + 1");
+    }
+}